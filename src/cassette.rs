@@ -0,0 +1,128 @@
+use crate::config::Config;
+use crate::http_utils;
+use crate::routes::{self, ForwardTarget};
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+
+/// The header that selects a cassette for a single request.
+pub static CASSETTE_HEADER: &str = "x-middleman-cassette";
+
+/// Requests with paths under this prefix are handled by middleman itself.
+pub static ADMIN_PREFIX: &str = "/__middleman/";
+
+/// Cassette names are letters, digits, `-`, `_` and `.`. They can't contain `/`,
+/// nested cassettes would share directories with the tapes of their parents.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+/// The cassette a request is recorded to and replayed from, the `x-middleman-cassette`
+/// header takes precedence over the cassette selected through the admin API.
+pub fn selected<T>(config: &Config, req: &Request<T>) -> Option<String> {
+    match req.headers().get(CASSETTE_HEADER) {
+        Some(name) => Some(String::from_utf8_lossy(name.as_bytes()).to_string()),
+        None => config.session.cassette(),
+    }
+}
+
+// The directory of the cassette of a request
+fn cassette_dir<T>(config: &Config, req: &Request<T>) -> String {
+    match selected(config, req) {
+        Some(cassette) => format!("{}/@cassettes/{}", config.tapes, cassette),
+        None => config.tapes.clone(),
+    }
+}
+
+/// The directory tapes are stored in for a request, routed requests are stored in
+/// the directory of their route in `@routes` inside the cassette, and requests to a
+/// forward proxy in a directory per host in `@hosts`. Names starting with `@` are
+/// never used for path segments, so these never collide with the tapes of a path.
+pub fn tapes_dir<T>(config: &Config, req: &Request<T>) -> String {
+    if let Some(target) = req.extensions().get::<ForwardTarget>() {
+        return format!("{}/@hosts/{}", cassette_dir(config, req), target.tapes);
+    }
+    match routes::route(config, req) {
+        Some(route) => format!("{}/@routes/{}", cassette_dir(config, req), route.tapes),
+        None => cassette_dir(config, req),
+    }
+}
+
+/// Is the cassette of a request sealed? In the `once` record mode, a cassette that
+/// already existed is only replayed. The default tapes directory is never sealed.
+pub fn sealed<T>(config: &Config, req: &Request<T>) -> bool {
+    selected(config, req).is_some() && config.session.cassette_existed(&cassette_dir(config, req))
+}
+
+/// A response for a request with an invalid `x-middleman-cassette` header, if it has one.
+pub fn invalid_header<T>(req: &Request<T>) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let name = req.headers().get(CASSETTE_HEADER)?;
+    if valid_name(&String::from_utf8_lossy(name.as_bytes())) {
+        return None;
+    }
+    Some(bad_request("Invalid cassette name"))
+}
+
+fn bad_request(message: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(http_utils::full(message))
+        .unwrap()
+}
+
+/// Handle a request to the admin API.
+///
+/// `GET /__middleman/cassette` returns the selected cassette, `PUT` with the name
+/// as body selects one and `DELETE` goes back to the default tapes directory.
+/// Selecting a cassette starts every sequence in it from the beginning.
+pub fn admin(config: &Config, req: &Request<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let path = &req.uri().path()[ADMIN_PREFIX.len()..];
+
+    match (req.method(), path) {
+        (&Method::GET, "cassette") => {
+            let cassette = config.session.cassette().unwrap_or_default();
+            Response::new(http_utils::full(cassette))
+        }
+        (&Method::PUT, "cassette") => {
+            let name = String::from_utf8_lossy(req.body()).trim().to_string();
+            if !valid_name(&name) {
+                return bad_request("Invalid cassette name");
+            }
+            println!("cassette {}", name);
+            config.session.select_cassette(Some(name));
+            Response::new(http_utils::empty())
+        }
+        (&Method::DELETE, "cassette") => {
+            println!("cassette ejected");
+            config.session.select_cassette(None);
+            Response::new(http_utils::empty())
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(http_utils::empty())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        assert!(valid_name("checkout-flow"));
+        assert!(valid_name("v1.2_beta"));
+    }
+
+    #[test]
+    fn rejects_names_that_leave_their_directory() {
+        for name in ["", ".", "..", "a/b", "../a", "a\\b", "@a"] {
+            assert!(!valid_name(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use bytes::Bytes;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use std::io::{self, Read, Write};
+
+// The content codings we can decode and encode, in order of preference
+const SUPPORTED: [&str; 3] = ["gzip", "br", "deflate"];
+
+pub fn decode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = vec![];
+    match encoding {
+        "gzip" | "x-gzip" => GzDecoder::new(body).read_to_end(&mut decoded)?,
+        // `deflate` in HTTP is the zlib format
+        "deflate" => ZlibDecoder::new(body).read_to_end(&mut decoded)?,
+        "br" => brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded)?,
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, encoding)),
+    };
+    Ok(decoded)
+}
+
+pub fn encode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        "gzip" | "x-gzip" => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        "br" => {
+            let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+            encoder.write_all(body)?;
+            Ok(encoder.into_inner())
+        }
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, encoding)),
+    }
+}
+
+/// Does a response have no body, whatever its headers say? Responses to `HEAD`
+/// requests keep the `Content-Length` of the body they would have had.
+pub fn is_bodiless(method: &Method, status: StatusCode) -> bool {
+    method == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+}
+
+/// Make the framing headers describe the collected body: the body is no longer
+/// chunked, and `Content-Length` is its actual length.
+pub fn normalize_framing(headers: &mut HeaderMap, len: usize) {
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+}
+
+/// Decode a compressed response, returns the response and the content coding it
+/// was decoded from. Responses we can't decode are returned as they are.
+pub fn decompress(resp: Response<Bytes>) -> (Response<Bytes>, Option<String>) {
+    let encoding = match resp.headers().get(CONTENT_ENCODING) {
+        Some(encoding) => encoding.to_str().unwrap_or("").trim().to_lowercase(),
+        None => return (resp, None),
+    };
+
+    match decode(&encoding, resp.body()) {
+        Ok(decoded) => {
+            let (mut parts, _) = resp.into_parts();
+            parts.headers.remove(CONTENT_ENCODING);
+            normalize_framing(&mut parts.headers, decoded.len());
+            (Response::from_parts(parts, decoded.into()), Some(encoding))
+        }
+        Err(e) => {
+            println!("Storing the {} encoded body as is: {}", encoding, e);
+            (resp, None)
+        }
+    }
+}
+
+// The quality values from an Accept-Encoding header, by content coding
+fn accepted<T>(req: &Request<T>) -> Vec<(String, f32)> {
+    let header = match req.headers().get(ACCEPT_ENCODING) {
+        Some(header) => header.to_str().unwrap_or(""),
+        None => return vec![],
+    };
+
+    header
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect()
+}
+
+// Pick the content coding to replay a decompressed body with, the one it was
+// recorded with is preferred
+fn negotiate<T>(req: &Request<T>, original: &str) -> Option<String> {
+    let accepted = accepted(req);
+    let quality = |coding: &str| {
+        accepted
+            .iter()
+            .find(|(name, _)| name == coding)
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for coding in std::iter::once(original).chain(SUPPORTED) {
+        let q = quality(coding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding.to_string())
+}
+
+/// Encode a body that was decompressed when it was recorded, in the content coding
+/// the client accepts. Clients that accept none of them get the plain body.
+pub fn compress_for<T>(req: &Request<T>, original: &str, resp: Response<Bytes>) -> Response<Bytes> {
+    let encoding = match negotiate(req, original) {
+        Some(encoding) => encoding,
+        None => return resp,
+    };
+
+    match encode(&encoding, resp.body()) {
+        Ok(encoded) => {
+            let (mut parts, _) = resp.into_parts();
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_str(&encoding).unwrap());
+            normalize_framing(&mut parts.headers, encoded.len());
+            Response::from_parts(parts, encoded.into())
+        }
+        Err(_) => resp,
+    }
+}
@@ -1,20 +1,58 @@
-use clap::Parser;
-use hickory_resolver::config::*;
-use hickory_resolver::TokioAsyncResolver;
+use crate::grpc::{self, GrpcConfig};
+use crate::headers::HeadersConfig;
+use crate::matching::MatchConfig;
+use crate::mitm::{self, Mitm};
+use crate::pool::{Pool, PoolConfig};
+use crate::redact::{Redaction, RedactionConfig};
+use crate::resolver::{Resolver, ResolverConfig};
+use crate::routes::{Route, RouteConfig, Upstream};
+use crate::session::{PlaybackConfig, Session};
+use clap::{Parser, ValueEnum};
+use prost_reflect::DescriptorPool;
 use serde::Deserialize;
-use std::net::IpAddr;
 use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use toml;
 
 static DEFAULT_CONFIG_FILENAME: &str = "middleman.toml";
+// Recorded bodies are kept in memory until they are complete
+static DEFAULT_MAX_RECORD_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// When to record a request to a tape and when to replay it.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordMode {
+    /// Replay known requests, record unknown requests unless the cassette already existed
+    #[default]
+    Once,
+    /// Replay known requests, always record unknown requests
+    #[value(name = "new_episodes")]
+    NewEpisodes,
+    /// Always send requests upstream and overwrite their tapes
+    All,
+    /// Only replay, never contact the upstream
+    None,
+}
+
+impl RecordMode {
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value {
+            "once" => Some(RecordMode::Once),
+            "new_episodes" => Some(RecordMode::NewEpisodes),
+            "all" => Some(RecordMode::All),
+            "none" => Some(RecordMode::None),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(
     author,
     version,
-    about = "Starts a reverse proxy to <UPSTREAM>, listens on <BIND>:<PORT>.\nRecords upstream responses to <TAPES> directory.\nReturns recorded response if url matches (does not call upstream in this case).\n\nThe optional header `x-middleman-passthrough` can be specified in http requests to middleman to pass a request through to the <UPSTREAM>.\nAny value other than the exact string \"false\" will be considered Truthy.\nThe `--replay-only` config flag takes precedence over the `x-middleman-passthrough` header."
+    about = "Starts a reverse proxy to <UPSTREAM>, listens on <BIND>:<PORT>.\nRecords upstream responses to <TAPES> directory.\nReturns recorded response if url matches (does not call upstream in this case).\n\nThe optional header `x-middleman-passthrough` can be specified in http requests to middleman to pass a request through to the <UPSTREAM>.\nAny value other than the exact string \"false\" will be considered Truthy.\nThe `--replay-only` config flag takes precedence over the `x-middleman-passthrough` header.\n\nThe optional header `x-middleman-record-mode` overrides the record mode for a single request, unless the record mode is `none`."
 )]
 pub struct CliArgs {
     /// the port to listen on
@@ -40,6 +78,12 @@ pub struct CliArgs {
         default_value_t = false
     )]
     upstream_tls: bool,
+    #[arg(
+        long,
+        help = "Talk HTTP/2 to a cleartext upstream, without negotiating it first (h2c with prior knowledge) [default: false]",
+        default_value_t = false
+    )]
+    upstream_h2c: bool,
 
     // Directory to store recordings
     #[arg(
@@ -60,6 +104,53 @@ pub struct CliArgs {
         default_value_t = false
     )]
     replay_only: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "When to record and when to replay, `--replay-only` is the same as `none` [default: once]"
+    )]
+    record_mode: Option<RecordMode>,
+    #[arg(
+        long,
+        help = "Re-record tapes older than this, e.g. 90s, 30m, 12h or 7d. Stale tapes are still replayed with --replay-only"
+    )]
+    max_age: Option<String>,
+    #[arg(
+        long,
+        help = "Answer with 504 Gateway Timeout when the upstream sends no response within this time, e.g. 30s or 2m [default: no timeout]"
+    )]
+    upstream_timeout: Option<String>,
+    #[arg(
+        long,
+        help = "Replay the tape of a request the upstream could not be reached for, when it has one, instead of answering with a gateway error [default: false]",
+        default_value_t = false
+    )]
+    fallback_to_tape: bool,
+    #[arg(
+        long,
+        help = "Act as a forward proxy: send requests for absolute URIs, as made by clients with HTTP_PROXY set, to the host in the URI [default: false]",
+        default_value_t = false
+    )]
+    forward_proxy: bool,
+    #[arg(
+        long,
+        help = "Intercept HTTPS in CONNECT tunnels with certificates signed by the local CA, so it can be recorded and replayed [default: false]",
+        default_value_t = false
+    )]
+    mitm: bool,
+    #[arg(
+        long,
+        help = "Create the local CA used by --mitm, write it to --ca-cert-file and --ca-key-file, then exit",
+        default_value_t = false
+    )]
+    generate_ca: bool,
+    #[arg(long, help = "The local CA certificate [default: middleman-ca.pem]")]
+    ca_cert_file: Option<String>,
+    #[arg(
+        long,
+        help = "The local CA private key [default: middleman-ca-key.pem]"
+    )]
+    ca_key_file: Option<String>,
     #[arg(
         long,
         help = "Should we listen for TLS connections? [default: false]",
@@ -68,6 +159,12 @@ pub struct CliArgs {
     listen_tls: bool,
     #[arg(long, help = "The TLS Listen Port", default_value_t = 5443)]
     tls_port: u16,
+    #[arg(
+        long,
+        help = "Move tapes recorded by older versions of middleman to where this version looks for them, then exit",
+        default_value_t = false
+    )]
+    migrate_tapes: bool,
     #[arg(long, help = "The TLS cert file")]
     cert_file: Option<String>,
     #[arg(long, help = "The TLS private key file")]
@@ -81,33 +178,73 @@ struct TomlConfig {
     tapes: Option<String>,
     bind: Option<String>,
     replay_only: Option<bool>,
+    record_mode: Option<RecordMode>,
+    max_age: Option<String>,
+    warn_stale: Option<bool>,
+    upstream_timeout: Option<String>,
+    fallback_to_tape: Option<bool>,
+    decompress: Option<bool>,
+    max_record_body_size: Option<usize>,
     pub listen_tls: Option<bool>,
     pub tls_port: Option<u16>,
     pub cert_file: Option<String>,
     pub private_key_file: Option<String>,
     pub upstream_tls: Option<bool>,
+    pub upstream_h2c: Option<bool>,
     pub upstream_port: Option<u16>,
+    pub matching: Option<MatchConfig>,
+    pub playback: Option<PlaybackConfig>,
+    pub redaction: Option<RedactionConfig>,
+    pub headers: Option<HeadersConfig>,
+    pub grpc: Option<GrpcConfig>,
+    pub resolver: Option<ResolverConfig>,
+    pub pool: Option<PoolConfig>,
+    pub routes: Option<Vec<RouteConfig>>,
+    pub forward_proxy: Option<bool>,
+    pub mitm: Option<bool>,
+    pub ca_cert_file: Option<String>,
+    pub ca_key_file: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
-    pub upstream: String,
-    pub upstream_ip: IpAddr,
-    pub upstream_tls: bool,
-    pub upstream_port: u16,
+    /// Where requests that match no route are sent
+    pub upstream: Option<Upstream>,
+    pub routes: Vec<Route>,
+    pub forward_proxy: bool,
+    /// Terminates TLS in CONNECT tunnels, when HTTPS interception is on
+    pub mitm: Option<Arc<Mitm>>,
+    pub generate_ca: bool,
+    pub ca_cert_file: String,
+    pub ca_key_file: String,
     pub tapes: String,
     pub bind: String,
-    pub replay_only: bool,
+    pub record_mode: RecordMode,
+    pub max_age: Option<Duration>,
+    pub warn_stale: bool,
+    pub upstream_timeout: Option<Duration>,
+    pub fallback_to_tape: bool,
+    pub decompress: bool,
+    pub max_record_body_size: usize,
     pub listen_tls: bool,
     pub tls_port: u16,
     pub cert_file: Option<String>,
     pub private_key_file: Option<String>,
+    pub matching: MatchConfig,
+    pub playback: PlaybackConfig,
+    pub redaction: Redaction,
+    pub headers: HeadersConfig,
+    pub descriptors: Option<DescriptorPool>,
+    pub resolver: Arc<Resolver>,
+    pub pool: Arc<Pool>,
+    pub session: Arc<Session>,
+    pub migrate_tapes: bool,
 }
 
 async fn read_config(args: &CliArgs) -> TomlConfig {
     if !Path::new(&args.config_path).exists() {
-        if &args.config_path != DEFAULT_CONFIG_FILENAME {
+        if args.config_path != DEFAULT_CONFIG_FILENAME {
             println!("Config file ({}) specified but not found", args.config_path);
             exit(1);
         }
@@ -125,7 +262,7 @@ async fn read_config(args: &CliArgs) -> TomlConfig {
                     exit(1);
                 }
             };
-            return x;
+            x
         }
         Err(_) => {
             eprintln!(
@@ -142,11 +279,7 @@ pub async fn get_config() -> Config {
 
     validate(&args, &toml);
 
-    let listen_tls = toml
-        .listen_tls
-        .or(Some(args.listen_tls))
-        .or(Some(false))
-        .unwrap();
+    let listen_tls = toml.listen_tls.or(Some(args.listen_tls)).unwrap_or(false);
     let cert_file = args.cert_file.or(toml.cert_file).or(None);
     let private_key_file = args.private_key_file.or(toml.private_key_file).or(None);
 
@@ -158,55 +291,169 @@ pub async fn get_config() -> Config {
         panic!("Trying to listen on TLS but --private-key-file file not provided.");
     }
 
-    let host = args.upstream.or(toml.upstream).unwrap();
-    let mut opts = ResolverOpts::default();
-    // We don't want to honor the hosts file, as we want to proxy to an actual host
-    opts.use_hosts_file = false;
-    let resolver = TokioAsyncResolver::tokio(ResolverConfig::google(), opts);
-    let upstream_ip = resolver
-        .lookup_ip(host.clone())
-        .await
-        .unwrap()
-        .iter()
-        .next()
-        .expect("Cloud not resolve upstream to an ip");
-
-    let upstream_tls = toml.upstream_tls.or(Some(args.upstream_tls)).unwrap();
-    let mut upstream_port = toml.upstream_port.or(Some(args.upstream_port)).unwrap();
-    if upstream_tls && upstream_port == 80 {
-        // Yes... if a user actually wants to use 80 with tls, it won't work
-        upstream_port = 443;
+    let upstream = match args.upstream.or(toml.upstream) {
+        Some(host) => Some(Upstream::new(
+            host,
+            toml.upstream_tls.unwrap_or(args.upstream_tls),
+            toml.upstream_port.unwrap_or(args.upstream_port),
+            toml.upstream_h2c.unwrap_or(args.upstream_h2c),
+        )),
+        None => None,
+    };
+
+    let mut routes = vec![];
+    for route in toml.routes.unwrap_or_default() {
+        let upstream = Upstream::new(
+            route.upstream.clone(),
+            route.upstream_tls,
+            route.upstream_port.unwrap_or(80),
+            route.upstream_h2c,
+        );
+        routes.push(Route::new(route, upstream).unwrap_or_else(|e| {
+            eprintln!("Invalid route: {}", e);
+            exit(1);
+        }));
     }
 
-    println!("Resolved {} to {}:{}", host, &upstream_ip, upstream_port);
+    let max_age = args.max_age.or(toml.max_age).map(|max_age| {
+        parse_duration(&max_age).unwrap_or_else(|| {
+            eprintln!(
+                "Invalid max age `{}`, expected e.g. 90s, 30m, 12h or 7d",
+                max_age
+            );
+            exit(1);
+        })
+    });
+
+    let upstream_timeout = args
+        .upstream_timeout
+        .or(toml.upstream_timeout)
+        .map(|timeout| {
+            parse_duration(&timeout).unwrap_or_else(|| {
+                eprintln!(
+                    "Invalid upstream timeout `{}`, expected e.g. 90s, 30m, 12h or 7d",
+                    timeout
+                );
+                exit(1);
+            })
+        });
+
+    let redaction = Redaction::new(toml.redaction.unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("Invalid redaction pattern: {}", e);
+        exit(1);
+    });
+
+    let descriptors = toml.grpc.unwrap_or_default().descriptor_set.map(|path| {
+        grpc::load_descriptors(&path).unwrap_or_else(|e| {
+            eprintln!("Unable to load the descriptor set `{}`: {}", path, e);
+            exit(1);
+        })
+    });
+
+    let ca_cert_file = args
+        .ca_cert_file
+        .or(toml.ca_cert_file)
+        .unwrap_or(mitm::DEFAULT_CA_CERT_FILE.to_string());
+    let ca_key_file = args
+        .ca_key_file
+        .or(toml.ca_key_file)
+        .unwrap_or(mitm::DEFAULT_CA_KEY_FILE.to_string());
+    let resolver = Resolver::new(toml.resolver.unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("Unable to set up the resolver: {}", e);
+        exit(1);
+    });
+
+    let pool = toml.pool.unwrap_or_default();
+    let idle_timeout = parse_duration(&pool.idle_timeout).unwrap_or_else(|| {
+        eprintln!(
+            "Invalid idle timeout `{}`, expected e.g. 90s, 30m, 12h or 7d",
+            pool.idle_timeout
+        );
+        exit(1);
+    });
+
+    let mitm = if toml.mitm.unwrap_or(args.mitm) && !args.generate_ca {
+        let mitm = Mitm::load(&ca_cert_file, &ca_key_file).unwrap_or_else(|e| {
+            eprintln!(
+                "Unable to load the CA for --mitm, create one with --generate-ca: {}",
+                e
+            );
+            exit(1);
+        });
+        Some(Arc::new(mitm))
+    } else {
+        None
+    };
 
     Config {
-        listen_tls: listen_tls,
-        tls_port: toml.tls_port.or(Some(args.tls_port)).unwrap(),
-        cert_file: cert_file,
-        private_key_file: private_key_file,
-
-        port: args.port.or(toml.port).or(Some(5050)).unwrap(),
-        upstream_ip: upstream_ip,
-        upstream: host,
-        upstream_tls: upstream_tls,
-        upstream_port: upstream_port,
-        tapes: args
-            .tapes
-            .or(toml.tapes)
-            .or(Some("tapes".to_string()))
-            .unwrap(),
-        bind: args
-            .bind
-            .or(toml.bind)
-            .or(Some("127.0.0.1".to_string()))
-            .unwrap(),
-        replay_only: toml.replay_only.or(Some(args.replay_only)).unwrap(),
+        listen_tls,
+        tls_port: toml.tls_port.unwrap_or(args.tls_port),
+        cert_file,
+        private_key_file,
+
+        port: args.port.or(toml.port).unwrap_or(5050),
+        upstream,
+        routes,
+        forward_proxy: toml.forward_proxy.unwrap_or(args.forward_proxy),
+        mitm,
+        generate_ca: args.generate_ca,
+        ca_cert_file,
+        ca_key_file,
+        tapes: args.tapes.or(toml.tapes).unwrap_or("tapes".to_string()),
+        bind: args.bind.or(toml.bind).unwrap_or("127.0.0.1".to_string()),
+        record_mode: if toml.replay_only.unwrap_or(args.replay_only) {
+            RecordMode::None
+        } else {
+            args.record_mode.or(toml.record_mode).unwrap_or_default()
+        },
+        max_age,
+        warn_stale: toml.warn_stale.unwrap_or(false),
+        upstream_timeout,
+        fallback_to_tape: toml.fallback_to_tape.unwrap_or(args.fallback_to_tape),
+        decompress: toml.decompress.unwrap_or(false),
+        max_record_body_size: toml
+            .max_record_body_size
+            .unwrap_or(DEFAULT_MAX_RECORD_BODY_SIZE),
+        matching: toml.matching.unwrap_or_default(),
+        playback: toml.playback.unwrap_or_default(),
+        redaction,
+        headers: toml.headers.unwrap_or_default(),
+        descriptors,
+        resolver: Arc::new(resolver),
+        pool: Arc::new(Pool::new(pool.max_idle_per_host, idle_timeout)),
+        session: Arc::new(Session::default()),
+        migrate_tapes: args.migrate_tapes,
     }
 }
 
+// Parse a duration like `90s`, `30m`, `12h` or `7d`, plain numbers are seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        "d" => number * 60 * 60 * 24,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
 fn validate(args: &CliArgs, toml: &TomlConfig) {
-    if args.upstream.clone().or(toml.upstream.clone()).is_none() {
+    // Routed and forwarded requests bring their own upstream
+    let routed = toml
+        .routes
+        .as_ref()
+        .is_some_and(|routes| !routes.is_empty())
+        || toml.forward_proxy.unwrap_or(args.forward_proxy)
+        || toml.mitm.unwrap_or(args.mitm);
+    let upstream_unused = args.migrate_tapes || args.generate_ca;
+    if !upstream_unused && !routed && args.upstream.clone().or(toml.upstream.clone()).is_none() {
         eprintln!("You did not provide an upstream");
         exit(1);
     }
@@ -0,0 +1,167 @@
+use crate::matching;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+// Characters that are not allowed (or have a special meaning) in file names on
+// at least one of the platforms we build for, plus the characters we use to
+// separate parts of tape names, to mark middleman's own directories and to
+// separate shortened names from their hash.
+const FILENAME: &AsciiSet = &CONTROLS
+    .add(b'/')
+    .add(b'\\')
+    .add(b':')
+    .add(b'*')
+    .add(b'?')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'|')
+    .add(b'%')
+    .add(b';')
+    .add(b'@')
+    .add(b'~');
+
+// Names Windows reserves for devices, with or without an extension
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Longer file names are shortened, most file systems allow 255 bytes
+const MAX_LENGTH: usize = 128;
+// The number of bytes kept in front of the hash when shortening a name
+const KEEP_LENGTH: usize = 64;
+
+/// Escape a value so it can safely be used as part of a file name.
+/// Decoding the result with percent decoding gives back the value.
+pub fn encode(value: &str) -> String {
+    utf8_percent_encode(value, FILENAME).to_string()
+}
+
+/// Encode a single URI path segment as a file name that stays inside its
+/// directory, `.` and `..` are escaped just like characters that are not
+/// allowed in file names.
+pub fn encode_segment(segment: &str) -> String {
+    if segment == "." || segment == ".." {
+        return segment.replace('.', "%2E");
+    }
+
+    let mut encoded = encode(segment);
+
+    let stem = encoded.split('.').next().unwrap_or("");
+    if RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        encoded = format!("%{:02X}{}", encoded.as_bytes()[0], &encoded[1..]);
+    }
+
+    // Windows drops trailing dots and spaces from file names
+    if encoded.ends_with('.') || encoded.ends_with(' ') {
+        let last = encoded.pop().unwrap();
+        encoded = format!("{}%{:02X}", encoded, last as u8);
+    }
+
+    shorten(encoded)
+}
+
+/// Shorten a file name that is too long for the file system, by replacing
+/// the end with a hash of the full name. Shortened names can not be decoded,
+/// but the tape still holds the full request.
+pub fn shorten(name: String) -> String {
+    if name.len() <= MAX_LENGTH {
+        return name;
+    }
+
+    let mut keep = KEEP_LENGTH;
+    // Don't cut an escape sequence or a multi byte character in half
+    while !name.is_char_boundary(keep) || name[..keep].ends_with('%') {
+        keep -= 1;
+    }
+    if keep >= 2 && name.as_bytes()[keep - 2] == b'%' {
+        keep -= 2;
+    }
+
+    format!("{}~{}", &name[..keep], matching::hash(name.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_dot_segments() {
+        assert_eq!(encode_segment("."), "%2E");
+        assert_eq!(encode_segment(".."), "%2E%2E");
+        assert_eq!(encode_segment("..."), "..%2E");
+    }
+
+    #[test]
+    fn encodes_escaped_dot_segments() {
+        assert_eq!(encode_segment("%2e%2e"), "%252e%252e");
+    }
+
+    #[test]
+    fn encodes_separators() {
+        assert_eq!(encode_segment("a\\b"), "a%5Cb");
+        assert_eq!(encode_segment("a/b"), "a%2Fb");
+        assert_eq!(encode_segment("c:"), "c%3A");
+    }
+
+    #[test]
+    fn encodes_reserved_windows_names() {
+        assert_eq!(encode_segment("CON"), "%43ON");
+        assert_eq!(encode_segment("nul.json"), "%6Eul.json");
+        assert_eq!(encode_segment("com1"), "%63om1");
+        assert_eq!(encode_segment("CONSOLE"), "CONSOLE");
+    }
+
+    #[test]
+    fn encodes_a_trailing_dot_or_space() {
+        assert_eq!(encode_segment("a."), "a%2E");
+        assert_eq!(encode_segment("a "), "a%20");
+    }
+
+    #[test]
+    fn keeps_names_up_to_the_max_length() {
+        let name = "a".repeat(MAX_LENGTH);
+        assert_eq!(shorten(name.clone()), name);
+    }
+
+    #[test]
+    fn shortens_longer_names() {
+        let name = "a".repeat(MAX_LENGTH + 1);
+        let shortened = shorten(name.clone());
+        assert_eq!(
+            shortened,
+            format!(
+                "{}~{}",
+                "a".repeat(KEEP_LENGTH),
+                matching::hash(name.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn does_not_split_an_escape() {
+        for before in [KEEP_LENGTH - 1, KEEP_LENGTH - 2] {
+            let name = format!("{}%2F{}", "a".repeat(before), "b".repeat(MAX_LENGTH));
+            let shortened = shorten(name);
+            assert!(shortened.starts_with(&format!("{}~", "a".repeat(before))));
+        }
+    }
+
+    #[test]
+    fn does_not_split_a_character() {
+        let name = format!("{}é{}", "a".repeat(KEEP_LENGTH - 1), "b".repeat(MAX_LENGTH));
+        let shortened = shorten(name);
+        assert!(shortened.starts_with(&format!("{}~", "a".repeat(KEEP_LENGTH - 1))));
+
+        let name = format!("{}€{}", "a".repeat(KEEP_LENGTH - 3), "b".repeat(MAX_LENGTH));
+        let shortened = shorten(name);
+        assert!(shortened.starts_with(&format!("{}€~", "a".repeat(KEEP_LENGTH - 3))));
+    }
+
+    #[test]
+    fn shortened_names_can_not_be_spelled_out() {
+        let name = "a".repeat(MAX_LENGTH + 1);
+        let shortened = shorten(name);
+        assert_ne!(encode_segment(&shortened), shortened);
+    }
+}
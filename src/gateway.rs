@@ -0,0 +1,70 @@
+use crate::http_utils;
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use std::fmt;
+
+/// Why a request could not be sent to its upstream.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The upstream host could not be looked up
+    Resolve(String),
+    /// None of the addresses of the upstream accepted a connection
+    Connect(String),
+    /// The TLS handshake with the upstream failed
+    Tls(String),
+    /// The upstream did not respond within the upstream timeout
+    Timeout(String),
+    /// The connection failed before the response head was read
+    Http(hyper::Error),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Resolve(message)
+            | UpstreamError::Connect(message)
+            | UpstreamError::Tls(message)
+            | UpstreamError::Timeout(message) => write!(f, "{}", message),
+            UpstreamError::Http(err) => write!(f, "the upstream connection failed: {}", err),
+        }
+    }
+}
+
+impl From<hyper::Error> for UpstreamError {
+    fn from(err: hyper::Error) -> Self {
+        UpstreamError::Http(err)
+    }
+}
+
+impl UpstreamError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    // The value of the `x-middleman-error` header
+    fn kind(&self) -> &'static str {
+        match self {
+            UpstreamError::Resolve(_) => "resolve",
+            UpstreamError::Connect(_) => "connect",
+            UpstreamError::Tls(_) => "tls",
+            UpstreamError::Timeout(_) => "timeout",
+            UpstreamError::Http(_) => "http",
+        }
+    }
+
+    /// The gateway response for the client, saying what went wrong in its body and
+    /// in the `x-middleman-error` header.
+    pub fn response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .status(self.status())
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header("x-middleman-error", self.kind())
+            .body(http_utils::full(format!("middleman: {}\n", self)))
+            .unwrap()
+    }
+}
@@ -0,0 +1,148 @@
+use crate::tape::{Body, Interaction};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use prost_reflect::prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The `[grpc]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GrpcConfig {
+    /// A file descriptor set, as written by `protoc --descriptor_set_out`, used to
+    /// store the messages of gRPC calls as JSON in tapes
+    pub descriptor_set: Option<String>,
+}
+
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/grpc"))
+}
+
+pub fn load_descriptors(path: &str) -> Result<DescriptorPool, String> {
+    let contents = std::fs::read(path).map_err(|e| e.to_string())?;
+    DescriptorPool::decode(contents.as_slice()).map_err(|e| e.to_string())
+}
+
+// gRPC calls are made to `/<package>.<Service>/<Method>`
+fn method(pool: &DescriptorPool, path: &str) -> Option<MethodDescriptor> {
+    let (service, method) = path.trim_start_matches('/').split_once('/')?;
+    pool.get_service_by_name(service)?
+        .methods()
+        .find(|m| m.name() == method)
+}
+
+// Each message is prefixed with a compressed flag and its length as a 4 byte big endian number
+fn split_messages(mut body: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut messages = vec![];
+    while !body.is_empty() {
+        let (header, rest) = body.split_at_checked(5)?;
+        if header[0] != 0 {
+            // Compressed messages are left alone
+            return None;
+        }
+        let len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let (message, rest) = rest.split_at_checked(len)?;
+        messages.push(message);
+        body = rest;
+    }
+    Some(messages)
+}
+
+fn decode(descriptor: &MessageDescriptor, body: &[u8]) -> Vec<Value> {
+    let messages = split_messages(body).unwrap_or_default();
+    let decoded: Result<Vec<Value>, String> = messages
+        .iter()
+        .map(|message| {
+            let message =
+                DynamicMessage::decode(descriptor.clone(), *message).map_err(|e| e.to_string())?;
+            serde_json::to_value(&message).map_err(|e| e.to_string())
+        })
+        .collect();
+    decoded.unwrap_or_else(|e| {
+        println!(
+            "Could not decode a {} message: {}",
+            descriptor.full_name(),
+            e
+        );
+        vec![]
+    })
+}
+
+/// Add the messages of a gRPC call, decoded to JSON, to an interaction. They're
+/// only there to be read, replaying uses the recorded bodies.
+pub fn decode_messages(pool: &DescriptorPool, interaction: &mut Interaction) {
+    let path = interaction.request.uri.split('?').next().unwrap_or("");
+    let method = match method(pool, path) {
+        Some(method) => method,
+        None => {
+            println!("No gRPC method {} in the descriptor set", path);
+            return;
+        }
+    };
+
+    interaction.request.grpc_messages =
+        decode(&method.input(), &interaction.request.body.to_bytes());
+    interaction.response.grpc_messages =
+        decode(&method.output(), &interaction.response.body.to_bytes());
+}
+
+// Each message framed the way `split_messages` expects it
+fn encode(descriptor: &MessageDescriptor, messages: &[Value]) -> Result<Vec<Vec<u8>>, String> {
+    messages
+        .iter()
+        .map(|message| {
+            let message = DynamicMessage::deserialize(descriptor.clone(), message)
+                .map_err(|e| format!("{} message: {}", descriptor.full_name(), e))?;
+            let message = message.encode_to_vec();
+            let mut framed = vec![0];
+            framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            framed.extend_from_slice(&message);
+            Ok(framed)
+        })
+        .collect()
+}
+
+fn to_body(messages: &[Vec<u8>]) -> Body {
+    Body::Base64(BASE64_STANDARD.encode(messages.concat()))
+}
+
+/// Put the messages of a gRPC call back into the bodies once they've been redacted,
+/// so the secrets are gone from the bodies as well. `request` and `response` say
+/// which of them changed. Fails when a redacted message no longer fits its type,
+/// like a placeholder in a number field.
+pub fn encode_messages(
+    pool: &DescriptorPool,
+    interaction: &mut Interaction,
+    request: bool,
+    response: bool,
+) -> Result<(), String> {
+    let path = interaction.request.uri.split('?').next().unwrap_or("");
+    let method = method(pool, path).ok_or_else(|| format!("No gRPC method {}", path))?;
+
+    if request {
+        let messages = encode(&method.input(), &interaction.request.grpc_messages)?;
+        interaction.request.body = to_body(&messages);
+    }
+    if response {
+        let messages = encode(&method.output(), &interaction.response.grpc_messages)?;
+        let chunks = &mut interaction.response.chunks;
+        let one_per_chunk = chunks.len() == messages.len()
+            && chunks
+                .iter()
+                .all(|chunk| split_messages(&chunk.data.to_bytes()).is_some_and(|m| m.len() == 1));
+        if one_per_chunk {
+            for (chunk, message) in chunks.iter_mut().zip(&messages) {
+                chunk.data = to_body(std::slice::from_ref(message));
+            }
+        } else {
+            // The chunks still hold the secrets, so the body is replayed in one piece
+            chunks.clear();
+        }
+        interaction.response.body = to_body(&messages);
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Headers to drop and headers to set, applied in that order.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HeaderRules {
+    pub drop: Vec<String>,
+    pub set: BTreeMap<String, String>,
+}
+
+/// The `[headers]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HeadersConfig {
+    /// Applied to the response before it is written to a tape, `drop` is applied
+    /// to the recorded request as well
+    pub record: HeaderRules,
+    /// Applied to every replayed response
+    pub replay: HeaderRules,
+}
+
+impl HeaderRules {
+    fn drops(&self, name: &str) -> bool {
+        self.drop.iter().any(|d| d.eq_ignore_ascii_case(name))
+    }
+
+    /// Drop headers from headers stored in a tape.
+    pub fn drop_from(&self, headers: &mut Vec<(String, String)>) {
+        headers.retain(|(name, _)| !self.drops(name));
+    }
+
+    /// Drop and set headers stored in a tape.
+    pub fn apply(&self, headers: &mut Vec<(String, String)>) {
+        self.drop_from(headers);
+        for (name, value) in &self.set {
+            headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            headers.push((name.to_lowercase(), value.clone()));
+        }
+    }
+
+    /// Drop and set headers of a response.
+    pub fn apply_to_map(&self, headers: &mut HeaderMap) {
+        for name in &self.drop {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in &self.set {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => println!("Ignoring invalid header {}: {}", name, value),
+            }
+        }
+    }
+}
@@ -1,20 +1,25 @@
+use crate::tee::Chunks;
 use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use http::header::HOST;
+use http::{HeaderMap, HeaderValue, Request, Version};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty, Full};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::Frame;
+use tokio::time::{self, Instant};
 
-pub fn start_of_body(payload: &Vec<u8>) -> usize {
+pub fn start_of_body(payload: &[u8]) -> usize {
     let mut start_of_body = 0;
     for i in 0..payload.len() {
         let bs = "\r\n".as_bytes();
-        if i + 3 < payload.len() {
-            if payload[i] == bs[0]
-                && payload[i + 1] == bs[1]
-                && payload[i + 2] == bs[0]
-                && payload[i + 3] == bs[1]
-            {
-                start_of_body = i + 4;
-                break;
-            }
+        if i + 3 < payload.len()
+            && payload[i] == bs[0]
+            && payload[i + 1] == bs[1]
+            && payload[i + 2] == bs[0]
+            && payload[i + 3] == bs[1]
+        {
+            start_of_body = i + 4;
+            break;
         }
     }
     start_of_body
@@ -31,3 +36,48 @@ pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .map_err(|never| match never {})
         .boxed()
 }
+
+/// A body that sends each chunk at its offset from now, multiplied by `scale`,
+/// followed by the trailers.
+pub fn delayed(
+    chunks: Chunks,
+    trailers: Option<HeaderMap>,
+    scale: f64,
+) -> BoxBody<Bytes, hyper::Error> {
+    let start = Instant::now();
+    let frames = stream::unfold(chunks.into_iter(), move |mut chunks| async move {
+        let (offset, data) = chunks.next()?;
+        time::sleep_until(start + offset.mul_f64(scale.max(0.0))).await;
+        Some((Ok(Frame::data(data)), chunks))
+    });
+    let trailers = stream::iter(trailers.map(|trailers| Ok(Frame::trailers(trailers))));
+    BodyExt::boxed(StreamBody::new(frames.chain(trailers)))
+}
+
+/// Requests received over HTTP/2, and requests to a forward proxy, carry their host
+/// in the URI, give them the form an HTTP/1.1 request has so they are matched and
+/// recorded the same way.
+pub fn origin_form<T>(mut req: Request<T>) -> Request<T> {
+    if req.version() == Version::HTTP_2 {
+        *req.version_mut() = Version::HTTP_11;
+    }
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => return req,
+    };
+
+    // Credentials in the URI are not part of the host
+    let host = match authority.port() {
+        Some(port) => format!("{}:{}", authority.host(), port),
+        None => authority.host().to_string(),
+    };
+    let host = HeaderValue::from_str(&host).unwrap();
+    req.headers_mut().entry(HOST).or_insert(host);
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    *req.uri_mut() = path.parse().unwrap();
+    req
+}
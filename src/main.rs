@@ -1,13 +1,32 @@
+mod cassette;
 mod clone;
+mod compression;
 mod config;
+mod filename;
+mod gateway;
+mod grpc;
+mod headers;
 mod http_utils;
+mod matching;
+mod migrate;
+mod mitm;
+mod pool;
 mod proxy;
+mod redact;
+mod resolver;
+mod routes;
+mod session;
+mod tape;
+mod tee;
 mod tokiort;
+mod websocket;
 
+use http_body_util::BodyExt;
 use hyper::service::service_fn;
 
 use http_body_util::combinators::BoxBody;
-use hyper::{server, Request, Response};
+use hyper::{Request, Response};
+use hyper_util::server::conn::auto;
 
 use bytes::Bytes;
 use std::fs::File;
@@ -16,20 +35,18 @@ use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::{rustls, TlsAcceptor};
 
-use crate::tokiort::TokioIo;
+use crate::tokiort::{TokioExecutor, TokioIo};
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
-use crate::clone::clone_incoming_response;
-use crate::config::Config;
+use crate::config::{Config, RecordMode};
+use crate::routes::ForwardTarget;
 
+use futures::future::{BoxFuture, FutureExt};
+use http::uri::Authority;
 use hyper::upgrade::Upgraded;
 use hyper::Method;
 
-fn host_addr(uri: &http::Uri) -> Option<String> {
-    uri.authority().and_then(|auth| Some(auth.to_string()))
-}
-
 // Create a TCP connection to host:port, build a tunnel between the connection and
 // the upgraded connection
 async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
@@ -44,6 +61,32 @@ async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
     Ok(())
 }
 
+// Terminate TLS on the upgraded connection with a certificate for the host it is
+// for, then serve the requests inside it like any other request. Boxed, as serving
+// the connection leads back to `proxy_handler`.
+fn intercept(
+    config: Config,
+    upgraded: Upgraded,
+    authority: Authority,
+) -> BoxFuture<'static, io::Result<()>> {
+    async move {
+        let mitm = config.mitm.clone().unwrap();
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let acceptor = mitm
+            .acceptor(host)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = acceptor.accept(TokioIo::new(upgraded)).await?;
+
+        let target = ForwardTarget::new(&authority, true);
+        serve_connection(config, TokioIo::new(stream), Some(target)).await;
+        Ok(())
+    }
+    .boxed()
+}
+
 async fn proxy_handler(
     config: &config::Config,
     req: Request<hyper::body::Incoming>,
@@ -66,11 +109,20 @@ async fn proxy_handler(
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
-        if let Some(addr) = host_addr(req.uri()) {
+        //
+        // With HTTPS interception on, the tunnel is not copied to the host but its
+        // TLS is terminated here, so its requests can be recorded and replayed.
+        if let Some(authority) = req.uri().authority().cloned() {
+            let config = config.clone();
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr).await {
+                        let result = if config.mitm.is_some() {
+                            intercept(config, upgraded, authority).await
+                        } else {
+                            tunnel(upgraded, authority.to_string()).await
+                        };
+                        if let Err(e) = result {
                             eprintln!("server io error: {}", e);
                         };
                     }
@@ -87,29 +139,93 @@ async fn proxy_handler(
             Ok(resp)
         }
     } else {
-        if config.replay_only {
-            proxy::replay(config, req).await
-        } else {
-            let passthrough = req.headers().contains_key("x-middleman-passthrough")
-                && req.headers().get("x-middleman-passthrough").unwrap() != "false";
-
-            if passthrough == true {
-                let (req, _) = clone::clone_incoming_request(req).await?;
-                let resp = proxy::make_request(config, req).await?;
-                let (_, resp) = clone_incoming_response(resp).await?;
-                return Ok(resp);
-            }
+        if let Some(resp) = cassette::invalid_header(&req) {
+            return Ok(resp);
+        }
+
+        let req = clone::buffer_incoming_request(req).await?;
+        let req = routes::forward(config, req);
+        let req = http_utils::origin_form(req);
+
+        if req.uri().path().starts_with(cassette::ADMIN_PREFIX) {
+            return Ok(cassette::admin(config, &req));
+        }
+
+        let record_mode = proxy::record_mode(config, &req);
+        if websocket::is_upgrade(&req) {
+            return websocket::handle(config, req, record_mode).await;
+        }
+
+        if record_mode == RecordMode::None {
+            return proxy::replay(config, &req).await;
+        }
+
+        let passthrough = req.headers().contains_key("x-middleman-passthrough")
+            && req.headers().get("x-middleman-passthrough").unwrap() != "false";
+
+        if passthrough {
+            return match proxy::make_request(config, req.map(http_utils::full)).await {
+                Ok(resp) => Ok(resp.map(|body| body.boxed())),
+                Err(err) => {
+                    println!("Failed to pass a request through: {}", err);
+                    Ok(err.response())
+                }
+            };
+        }
+
+        let recording_name = proxy::recording_name(config, &req);
+        // In sequential mode, tapes recorded in this session keep recording
+        let recording = config.playback.sequential && config.session.is_recorded(&recording_name);
+        let known = proxy::recording_exists(&recording_name) && !recording;
+
+        if known && record_mode != RecordMode::All && proxy::recording_is_stale(config, &req).await
+        {
+            println!("refresh  for     {} {}", req.method(), req.uri().path());
+            return match proxy::record_upstream(config, req.clone()).await {
+                Ok(resp) => Ok(resp),
+                Err(err) => {
+                    println!("Failed to refresh a stale tape: {}", err);
+                    proxy::replay(config, &req).await
+                }
+            };
+        }
 
-            if proxy::recording_exists(&proxy::recording_name(&config.tapes, &req)) {
-                return proxy::replay(config, req).await;
+        match record_mode {
+            RecordMode::All => {}
+            RecordMode::Once if known || cassette::sealed(config, &req) => {
+                return proxy::replay(config, &req).await;
             }
+            _ if known => return proxy::replay(config, &req).await,
+            _ => {}
+        }
 
-            let (req, new_req) = clone::clone_incoming_request(req).await?;
-            let resp = proxy::make_request(config, new_req).await?;
-            let (resp, new_resp) = clone::clone_incoming_response(resp).await?;
-            let _ = proxy::record(&config, req, new_resp).await;
-            Ok(resp)
+        match proxy::record_upstream(config, req.clone()).await {
+            Ok(resp) => Ok(resp),
+            Err(err) => proxy::upstream_failed(config, &req, err).await,
+        }
+    }
+}
+
+// Serve HTTP/1.1 or HTTP/2, whichever the client speaks. Requests on connections
+// from an intercepted CONNECT tunnel get the tunnel's target.
+async fn serve_connection<I>(config: Config, io: I, target: Option<ForwardTarget>)
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let config = Arc::new(config);
+    let service = service_fn(move |mut req: Request<hyper::body::Incoming>| {
+        if let Some(target) = &target {
+            req.extensions_mut().insert(target.clone());
         }
+        let config = config.clone();
+        async move { proxy_handler(&config, req).await }
+    });
+
+    if let Err(err) = auto::Builder::new(TokioExecutor)
+        .serve_connection_with_upgrades(io, service)
+        .await
+    {
+        println!("Failed to serve connection: {:?}", err);
     }
 }
 
@@ -141,10 +257,11 @@ async fn listen_and_serve_https(config: &Config) -> Result<(), Box<dyn std::erro
         let private_key =
             rustls_pemfile::private_key(&mut BufReader::new(private_key_file.unwrap()))?.unwrap();
 
-        let server_config = rustls::ServerConfig::builder()
+        let mut server_config = rustls::ServerConfig::builder()
             .with_no_client_auth()
             .with_single_cert(certs, private_key)
             .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         let acceptor = TlsAcceptor::from(Arc::new(server_config));
         loop {
@@ -162,18 +279,7 @@ async fn listen_and_serve_https(config: &Config) -> Result<(), Box<dyn std::erro
             let stream = stream.unwrap();
 
             let io = TokioIo::new(stream);
-            tokio::task::spawn(async move {
-                if let Err(err) = server::conn::http1::Builder::new()
-                    .serve_connection(
-                        io,
-                        service_fn(|req| async { proxy_handler(&config, req).await }),
-                    )
-                    .with_upgrades()
-                    .await
-                {
-                    println!("Failed to serve connection: {:?}", err);
-                }
-            });
+            tokio::task::spawn(serve_connection(config, io, None));
         }
     }
     Ok(())
@@ -190,19 +296,7 @@ async fn listen_and_serve_http(config: &Config) -> Result<(), Box<dyn std::error
     loop {
         let (stream, _) = listener.accept().await?;
         let io = TokioIo::new(stream);
-        let config = config.clone();
-        tokio::task::spawn(async move {
-            if let Err(err) = server::conn::http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(|req| async { proxy_handler(&config, req).await }),
-                )
-                .with_upgrades()
-                .await
-            {
-                println!("Failed to serve connection: {:?}", err);
-            }
-        });
+        tokio::task::spawn(serve_connection(config.clone(), io, None));
     }
 }
 
@@ -210,6 +304,23 @@ async fn listen_and_serve_http(config: &Config) -> Result<(), Box<dyn std::error
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = config::get_config().await;
 
+    if config.migrate_tapes {
+        migrate::migrate_tapes(&config).await;
+        return Ok(());
+    }
+
+    if config.generate_ca {
+        if let Err(e) = mitm::generate_ca(&config.ca_cert_file, &config.ca_key_file) {
+            eprintln!("Unable to create the CA: {}", e);
+            std::process::exit(1);
+        }
+        println!(
+            "Created a CA in {} and {}\nClients have to trust {} for --mitm, e.g. with `curl --cacert {}`, SSL_CERT_FILE or NODE_EXTRA_CA_CERTS",
+            config.ca_cert_file, config.ca_key_file, config.ca_cert_file, config.ca_cert_file
+        );
+        return Ok(());
+    }
+
     let (a, b) = tokio::join!(
         listen_and_serve_http(&config),
         listen_and_serve_https(&config)
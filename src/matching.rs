@@ -0,0 +1,238 @@
+use crate::filename::{self, encode};
+use crate::redact::Redaction;
+use crate::{grpc, websocket};
+use bytes::Bytes;
+use http::{Request, Uri};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// The `[matching]` table in middleman.toml, controls which parts of a request
+/// decide what tape it is recorded to and replayed from.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MatchConfig {
+    /// Include the query string in the tape name
+    pub query: bool,
+    /// Sort query parameters, so `?a=1&b=2` and `?b=2&a=1` share a tape
+    pub sort_query: bool,
+    /// Query parameters that are left out of the tape name, e.g. cache busters
+    pub ignore_query_params: Vec<String>,
+    /// Include a hash of the request body in the tape name
+    pub body: bool,
+    /// The methods for which the body takes part in matching
+    pub body_methods: Vec<String>,
+    /// Parse JSON bodies and hash a canonical form, so key order and whitespace do not matter
+    pub canonical_json: bool,
+    /// Request headers whose values take part in matching
+    pub headers: Vec<String>,
+    /// Extra headers that take part in matching for some paths only
+    pub rules: Vec<MatchRule>,
+}
+
+/// A `[[matching.rules]]` entry in middleman.toml.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MatchRule {
+    /// The rule applies to requests whose path starts with this prefix
+    pub path_prefix: String,
+    pub headers: Vec<String>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            query: true,
+            sort_query: false,
+            ignore_query_params: vec![],
+            body: false,
+            body_methods: vec!["POST".to_string(), "PUT".to_string(), "PATCH".to_string()],
+            canonical_json: false,
+            headers: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+/// The query string as it takes part in matching, `None` if it does not. The values
+/// of redacted parameters are hashed.
+pub fn query_key(config: &MatchConfig, redaction: &Redaction, uri: &Uri) -> Option<String> {
+    if !config.query {
+        return None;
+    }
+
+    let mut params: Vec<&str> = uri
+        .query()?
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or("");
+            !config.ignore_query_params.iter().any(|i| i == name)
+        })
+        .collect();
+
+    if config.sort_query {
+        params.sort();
+    }
+
+    if params.is_empty() {
+        return None;
+    }
+
+    let params: Vec<String> = params
+        .into_iter()
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) if redaction.is_secret_query_param(name, value) => {
+                format!("{}={}", name, hash(value.as_bytes()))
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    Some(params.join("&"))
+}
+
+/// A short, stable hex digest of some bytes.
+pub fn hash(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compact JSON with the keys of objects sorted, whatever order the map keeps them in
+fn canonical(json: &Value) -> String {
+    match json {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            let entries: Vec<String> = entries
+                .into_iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), canonical(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        _ => json.to_string(),
+    }
+}
+
+/// The hash of the request body as it takes part in matching, `None` if it does not.
+pub fn body_key(config: &MatchConfig, req: &Request<Bytes>) -> Option<String> {
+    // Every call to a gRPC method goes to the same path, the request message tells them apart
+    if grpc::is_grpc(req.headers()) {
+        return Some(hash(req.body()));
+    }
+
+    if !config.body
+        || !config
+            .body_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(req.method().as_str()))
+    {
+        return None;
+    }
+
+    if config.canonical_json {
+        if let Ok(json) = serde_json::from_slice::<Value>(req.body()) {
+            return Some(hash(canonical(&json).as_bytes()));
+        }
+    }
+
+    Some(hash(req.body()))
+}
+
+// Headers that carry credentials, their values are hashed instead of being written
+// to the tape name in plain text, like those of redacted headers.
+const SENSITIVE_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// The `name=value` pairs of the request headers that take part in matching.
+pub fn header_keys<T>(
+    config: &MatchConfig,
+    redaction: &Redaction,
+    req: &Request<T>,
+) -> Vec<String> {
+    let path = req.uri().path();
+    let mut names: Vec<String> = config
+        .headers
+        .iter()
+        .chain(
+            config
+                .rules
+                .iter()
+                .filter(|rule| path.starts_with(&rule.path_prefix))
+                .flat_map(|rule| rule.headers.iter()),
+        )
+        .map(|name| name.to_lowercase())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let value = req.headers().get(&name)?;
+            let text = String::from_utf8_lossy(value.as_bytes());
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str())
+                || redaction.is_secret_header(&name, &text)
+            {
+                hash(value.as_bytes())
+            } else {
+                text.to_string()
+            };
+            Some(format!("{}={}", name, value))
+        })
+        .collect()
+}
+
+/// The tape file name for a request, the method followed by any `;` separated
+/// parts of the request that take part in matching, shortened when it gets too long.
+/// Redacted values are hashed, so they don't end up in the name.
+pub fn tape_file_name(config: &MatchConfig, redaction: &Redaction, req: &Request<Bytes>) -> String {
+    let mut name = req.method().as_str().to_string();
+
+    if let Some(query) = query_key(config, redaction, req.uri()) {
+        name.push_str(";q=");
+        name.push_str(&encode(&query));
+    }
+
+    if let Some(body) = body_key(config, req) {
+        name.push_str(";b=");
+        name.push_str(&body);
+    }
+
+    for header in header_keys(config, redaction, req) {
+        name.push_str(";h.");
+        name.push_str(&encode(&header));
+    }
+
+    // WebSocket connections are kept apart from plain requests to the same path
+    if websocket::is_upgrade(req) {
+        name.push_str(";ws");
+    }
+
+    filename::shorten(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_the_keys_of_json_objects() {
+        let a = serde_json::json!({"b": [{"d": 1, "c": null}], "a": "x"});
+        let b: Value =
+            serde_json::from_str(r#"{ "a": "x", "b": [ { "c": null, "d": 1 } ] }"#).unwrap();
+        assert_eq!(canonical(&a), r#"{"a":"x","b":[{"c":null,"d":1}]}"#);
+        assert_eq!(canonical(&a), canonical(&b));
+    }
+
+    #[test]
+    fn escapes_keys() {
+        let json = serde_json::json!({"a\"b": 1});
+        assert_eq!(canonical(&json), r#"{"a\"b":1}"#);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::Config;
+use crate::{proxy, tape};
+use bytes::Bytes;
+use http::Request;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Tapes written before tape files started with `@` are named after the request
+// method, optionally followed by `;` separated matching parts and `.json`
+fn is_legacy_tape(file_name: &str) -> bool {
+    if file_name.starts_with('@') || file_name.ends_with(".request") {
+        return false;
+    }
+    let stem = file_name.strip_suffix(".json").unwrap_or(file_name);
+    let method = stem.split(';').next().unwrap_or("");
+    !method.is_empty() && method.chars().all(|c| c.is_ascii_uppercase())
+}
+
+fn find_legacy_tapes(dir: &Path, tapes: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_legacy_tapes(&path, tapes);
+        } else if is_legacy_tape(&entry.file_name().to_string_lossy()) {
+            tapes.push(path);
+        }
+    }
+}
+
+// Remove directories that are empty after their tapes were moved
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                remove_empty_dirs(&entry.path());
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+    }
+}
+
+/// Move every tape in the tapes directory that was written by an older version of
+/// middleman to where this version looks for it. Tapes are rewritten in the current
+/// format, and stored under the name the current `[matching]` config gives them.
+pub async fn migrate_tapes(config: &Config) {
+    let root = Path::new(&config.tapes);
+    let mut legacy_tapes = vec![];
+    find_legacy_tapes(root, &mut legacy_tapes);
+
+    for legacy_tape in legacy_tapes {
+        let file_name = legacy_tape.file_name().unwrap().to_string_lossy();
+        let recording_name = legacy_tape.to_string_lossy();
+        let recording_name = recording_name
+            .strip_suffix(".json")
+            .unwrap_or(&recording_name);
+        let dirs: Vec<String> = legacy_tape
+            .parent()
+            .unwrap()
+            .strip_prefix(root)
+            .unwrap()
+            .iter()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .collect();
+
+        // Raw tapes without a `.request` file only have their location to go on,
+        // the directories are the path and the file name is the method
+        let method = file_name.split(['.', ';']).next().unwrap();
+        let located = Request::builder()
+            .method(method)
+            .uri(format!("/{}", dirs.join("/")))
+            .body(Bytes::new());
+        let located = match located {
+            Ok(located) => located,
+            Err(_) => {
+                println!(
+                    "skipping {}, can't tell what request it is for",
+                    recording_name
+                );
+                continue;
+            }
+        };
+
+        let tape = match tape::load(recording_name, &located).await {
+            Ok(Some(tape)) => tape,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("skipping {}, {}", recording_name, e);
+                continue;
+            }
+        };
+        let req = tape.interactions[0].request.to_request();
+
+        // Older versions dropped empty path segments, whatever is left in front of
+        // the path is the tapes directory of the cassette
+        let segments = req.uri().path().split('/').filter(|s| !s.is_empty());
+        let cassette_dirs = match dirs.len().checked_sub(segments.count()) {
+            Some(len) => &dirs[..len],
+            None => {
+                println!(
+                    "skipping {}, it is not stored under its path",
+                    recording_name
+                );
+                continue;
+            }
+        };
+        let mut dir = config.tapes.clone();
+        for cassette_dir in cassette_dirs {
+            dir = format!("{}/{}", dir, cassette_dir);
+        }
+        let new_recording_name =
+            proxy::recording_name_in(&dir, &config.matching, &config.redaction, &req);
+
+        println!(
+            "migrate  {} -> {}",
+            legacy_tape.display(),
+            tape::path(&new_recording_name)
+        );
+        tape::save(&new_recording_name, &tape).await;
+        let _ = fs::remove_file(&legacy_tape);
+        let _ = fs::remove_file(format!("{}.request", recording_name));
+    }
+
+    remove_empty_dirs(root);
+}
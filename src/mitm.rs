@@ -0,0 +1,137 @@
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub static DEFAULT_CA_CERT_FILE: &str = "middleman-ca.pem";
+pub static DEFAULT_CA_KEY_FILE: &str = "middleman-ca-key.pem";
+
+/// Terminates TLS for CONNECT tunnels, with certificates for the tunnelled hosts
+/// signed by a local CA.
+pub struct Mitm {
+    ca: CertificateDer<'static>,
+    issuer: Issuer<'static, KeyPair>,
+    // The TLS config for every host a certificate was made for
+    hosts: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl fmt::Debug for Mitm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mitm").finish_non_exhaustive()
+    }
+}
+
+/// Create a CA and write its certificate and key to the given files. Existing
+/// files are never overwritten.
+pub fn generate_ca(cert_file: &str, key_file: &str) -> Result<(), String> {
+    for file in [cert_file, key_file] {
+        if Path::new(file).exists() {
+            return Err(format!("{} already exists", file));
+        }
+    }
+
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "middleman CA");
+    name.push(DnType::OrganizationName, "middleman");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(3650);
+
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+    let cert = params.self_signed(&key).map_err(|e| e.to_string())?;
+
+    fs::write(cert_file, cert.pem()).map_err(|e| format!("{}: {}", cert_file, e))?;
+    write_private(key_file, &key.serialize_pem()).map_err(|e| format!("{}: {}", key_file, e))?;
+    Ok(())
+}
+
+// Write a file only its owner can read
+fn write_private(file: &str, contents: &str) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(file)?.write_all(contents.as_bytes())
+}
+
+impl Mitm {
+    /// Load the CA generated by [`generate_ca`].
+    pub fn load(cert_file: &str, key_file: &str) -> Result<Self, String> {
+        let cert = fs::read_to_string(cert_file).map_err(|e| format!("{}: {}", cert_file, e))?;
+        let key = fs::read_to_string(key_file).map_err(|e| format!("{}: {}", key_file, e))?;
+
+        let key = KeyPair::from_pem(&key).map_err(|e| format!("{}: {}", key_file, e))?;
+        let ca = rustls_pemfile::certs(&mut cert.as_bytes())
+            .next()
+            .ok_or(format!("{}: no certificate found", cert_file))?
+            .map_err(|e| format!("{}: {}", cert_file, e))?;
+        let issuer =
+            Issuer::from_ca_cert_pem(&cert, key).map_err(|e| format!("{}: {}", cert_file, e))?;
+
+        Ok(Mitm {
+            ca,
+            issuer,
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// A TLS acceptor presenting a certificate for `host`, made the first time the
+    /// host is seen.
+    pub fn acceptor(&self, host: &str) -> Result<TlsAcceptor, String> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(config) = hosts.get(host) {
+            return Ok(TlsAcceptor::from(config.clone()));
+        }
+
+        let config = Arc::new(self.server_config(host)?);
+        hosts.insert(host.to_string(), config.clone());
+        Ok(TlsAcceptor::from(config))
+    }
+
+    fn server_config(&self, host: &str) -> Result<ServerConfig, String> {
+        let mut params =
+            CertificateParams::new(vec![host.to_string()]).map_err(|e| e.to_string())?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        // Clients reject server certificates that are valid for too long
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+
+        let key = KeyPair::generate().map_err(|e| e.to_string())?;
+        let cert = params
+            .signed_by(&key, &self.issuer)
+            .map_err(|e| e.to_string())?;
+
+        let chain = vec![cert.der().clone(), self.ca.clone()];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| e.to_string())?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
@@ -0,0 +1,163 @@
+use crate::routes::Upstream;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::client::conn::{http1, http2};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The body of requests sent upstream.
+pub type Body = BoxBody<Bytes, hyper::Error>;
+
+// Connections by upstream, with when they were last used
+type Connections<T> = Mutex<HashMap<String, T>>;
+
+/// The `[pool]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// The most idle connections kept open per upstream, 0 opens a connection for
+    /// every request
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept open, e.g. 90s or 5m
+    pub idle_timeout: String,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle_per_host: 8,
+            idle_timeout: "90s".to_string(),
+        }
+    }
+}
+
+/// A connection to an upstream that requests can be sent on.
+pub enum Sender {
+    Http1(http1::SendRequest<Body>),
+    Http2(http2::SendRequest<Body>),
+}
+
+/// Connections to upstreams kept open to send later requests on.
+#[derive(Default)]
+pub struct Pool {
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+    // HTTP/1.1 connections without a request in flight
+    idle: Connections<Vec<(Instant, http1::SendRequest<Body>)>>,
+    // An HTTP/2 connection takes any number of requests at once, so one is shared
+    shared: Connections<(Instant, http2::SendRequest<Body>)>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("max_idle_per_host", &self.max_idle_per_host)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+// Connections are kept per upstream and the way it is talked to
+fn key(upstream: &Upstream) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        upstream.host, upstream.port, upstream.tls, upstream.h2c
+    )
+}
+
+impl Pool {
+    pub fn new(max_idle_per_host: usize, idle_timeout: Duration) -> Self {
+        Pool {
+            max_idle_per_host,
+            idle_timeout,
+            ..Default::default()
+        }
+    }
+
+    /// A connection to the upstream that is still open and has not been idle for
+    /// too long, if there is one.
+    pub fn checkout(&self, upstream: &Upstream) -> Option<Sender> {
+        let key = key(upstream);
+
+        let mut shared = self.shared.lock().unwrap();
+        if let Some((last_used, sender)) = shared.get_mut(&key) {
+            if !sender.is_closed() && last_used.elapsed() < self.idle_timeout {
+                *last_used = Instant::now();
+                return Some(Sender::Http2(sender.clone()));
+            }
+            shared.remove(&key);
+        }
+        drop(shared);
+
+        let mut idle = self.idle.lock().unwrap();
+        let senders = idle.get_mut(&key)?;
+        while let Some((last_used, sender)) = senders.pop() {
+            if sender.is_ready() && last_used.elapsed() < self.idle_timeout {
+                return Some(Sender::Http1(sender));
+            }
+        }
+        None
+    }
+
+    /// Share a new HTTP/2 connection with later requests to the upstream.
+    pub fn share(self: &Arc<Self>, upstream: &Upstream, sender: &http2::SendRequest<Body>) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+        let key = key(upstream);
+        self.shared
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (Instant::now(), sender.clone()));
+
+        // Close the connection once no request was sent on it for the timeout
+        let pool = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(pool.idle_timeout).await;
+                let mut shared = pool.shared.lock().unwrap();
+                match shared.get(&key) {
+                    Some((last_used, _)) if last_used.elapsed() >= pool.idle_timeout => {
+                        shared.remove(&key);
+                        return;
+                    }
+                    Some(_) => {}
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// Give an HTTP/1.1 connection back once the response to its request has been
+    /// read, connections that are closed or upgraded by then are dropped.
+    pub fn checkin(self: &Arc<Self>, upstream: &Upstream, mut sender: http1::SendRequest<Body>) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+        let pool = self.clone();
+        let key = key(upstream);
+        tokio::task::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            {
+                let mut idle = pool.idle.lock().unwrap();
+                let senders = idle.entry(key.clone()).or_default();
+                if senders.len() >= pool.max_idle_per_host {
+                    return;
+                }
+                senders.push((Instant::now(), sender));
+            }
+
+            // Close the connection if it is still idle after the timeout
+            tokio::time::sleep(pool.idle_timeout).await;
+            let mut idle = pool.idle.lock().unwrap();
+            if let Some(senders) = idle.get_mut(&key) {
+                senders.retain(|(last_used, _)| last_used.elapsed() < pool.idle_timeout);
+            }
+        });
+    }
+}
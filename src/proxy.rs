@@ -1,79 +1,277 @@
-use crate::config::Config;
-use crate::tokiort::TokioIo;
-use crate::{clone, config, http_utils};
+use crate::config::{Config, RecordMode};
+use crate::gateway::UpstreamError;
+use crate::matching::MatchConfig;
+use crate::pool::{self, Sender};
+use crate::redact::Redaction;
+use crate::routes::{self, Upstream};
+use crate::tape::{self, Body, Tape};
+use crate::tokiort::{TokioExecutor, TokioIo};
+use crate::{cassette, compression, config, filename, grpc, http_utils, matching, tee, websocket};
 use bytes::Bytes;
-use http::{Request, Response};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, HOST, WARNING};
+use http::uri::Authority;
+use http::{HeaderValue, Request, Response, Version};
 use http_body_util::combinators::BoxBody;
-use http_body_util::BodyExt;
-use hyper::body;
 use hyper::body::Incoming;
-use hyper::client::conn::http1::Builder;
+use hyper::client::conn::{http1, http2, TrySendError};
 use native_tls::TlsConnector as NativeTlsConnector;
-use std::path::Path;
-use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_native_tls::TlsConnector;
 
 pub fn recording_exists(recording_name: &str) -> bool {
-    Path::new(&recording_name).exists()
+    tape::exists(recording_name)
 }
 
-pub fn recording_name<T>(folder: &str, req: &Request<T>) -> String {
-    let path: &str = req.uri().path();
-    let method: &str = req.method().as_str();
-    format!("{}/{}/{}", folder, path, method)
+/// The location of the tape for a request.
+pub fn recording_name(config: &Config, req: &Request<Bytes>) -> String {
+    recording_name_in(
+        &cassette::tapes_dir(config, req),
+        &config.matching,
+        &config.redaction,
+        req,
+    )
+}
+
+/// The location of the tape for a request in a tapes directory. Every path segment
+/// is encoded, so the tape is always inside the directory. Names starting with `@`
+/// are never used for path segments, so tapes are `@` followed by the tape file name
+/// and empty segments, e.g. from a trailing slash, are `@` directories.
+pub fn recording_name_in(
+    dir: &str,
+    match_config: &MatchConfig,
+    redaction: &Redaction,
+    req: &Request<Bytes>,
+) -> String {
+    let mut recording_name = dir.to_string();
+
+    let path = req.uri().path();
+    let path = path.strip_prefix('/').unwrap_or(path);
+    if !path.is_empty() {
+        for segment in path.split('/') {
+            recording_name.push('/');
+            if segment.is_empty() {
+                recording_name.push('@');
+            } else {
+                recording_name.push_str(&filename::encode_segment(segment));
+            }
+        }
+    }
+
+    recording_name.push_str("/@");
+    recording_name.push_str(&matching::tape_file_name(match_config, redaction, req));
+    recording_name
+}
+
+pub static RECORD_MODE_HEADER: &str = "x-middleman-record-mode";
+
+/// The record mode for a request, `none` can not be overridden per request.
+/// Requests without an upstream are only replayed.
+pub fn record_mode<T>(config: &Config, req: &Request<T>) -> RecordMode {
+    if config.record_mode == RecordMode::None || routes::upstream(config, req).is_none() {
+        return RecordMode::None;
+    }
+
+    req.headers()
+        .get(RECORD_MODE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(RecordMode::from_header)
+        .unwrap_or(config.record_mode)
+}
+
+/// Is there a tape for the request that is older than `max_age`?
+pub async fn recording_is_stale(config: &Config, req: &Request<Bytes>) -> bool {
+    if config.max_age.is_none() {
+        return false;
+    }
+    match tape::load(&recording_name(config, req), req).await {
+        Ok(Some(tape)) => tape.is_stale(config.max_age),
+        // A tape that can't be read is reported when it is replayed
+        _ => false,
+    }
 }
 
 pub async fn replay(
     config: &config::Config,
-    req: Request<body::Incoming>,
+    req: &Request<Bytes>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    if !recording_exists(&recording_name(&config.tapes, &req)) {
-        println!(
-            "Not Impl for {} {} {}",
-            501,
-            &req.method().to_string(),
-            &req.uri().path().to_string()
+    let recording_path = recording_name(config, req);
+    let tape = match tape::load(&recording_path, req).await {
+        Ok(Some(tape)) => tape,
+        Ok(None) => return Ok(not_recorded(req)),
+        Err(err) => return Ok(unreadable_tape(req, &recording_path, &err)),
+    };
+
+    let index = if config.playback.sequential {
+        match config.session.next_interaction(
+            &recording_path,
+            tape.interactions.len(),
+            config.playback.on_exhausted,
+        ) {
+            Some(index) => index,
+            None => {
+                println!(
+                    "Exhausted for {} {}",
+                    &req.method().to_string(),
+                    &req.uri().path().to_string()
+                );
+                return Ok(not_recorded(req));
+            }
+        }
+    } else {
+        0
+    };
+    let mut response = tape.interactions[index].response.clone();
+    config.redaction.restore(&mut response);
+    let mut resp = response.to_response(req.method());
+    if let Some(content_encoding) = &response.content_encoding {
+        resp = compression::compress_for(req, content_encoding, resp);
+    }
+    if !response.chunks.is_empty() {
+        resp.headers_mut().remove(CONTENT_LENGTH);
+    }
+    let trailers = response.trailers_map();
+    config.headers.replay.apply_to_map(resp.headers_mut());
+    if config.warn_stale && tape.is_stale(config.max_age) {
+        resp.headers_mut().append(
+            WARNING,
+            HeaderValue::from_static("110 - \"Response is Stale\""),
         );
+    }
 
-        let mut resp = Response::builder().status(501);
+    let method = req.method().clone();
+    let path = req.uri().path();
+    println!(
+        "playback for {} {} {}",
+        resp.status().as_u16(),
+        &method,
+        &path
+    );
 
-        if req.headers().get("accept").is_some() {
-            resp = resp.header("accept", req.headers().get("accept").unwrap());
-        }
-        return Ok(resp.body(http_utils::empty()).unwrap());
+    if response.chunks.is_empty() && trailers.is_none() {
+        return Ok(resp.map(http_utils::full));
     }
-    let recording_path = recording_name(&config.tapes, &req);
-    let c: Vec<u8> = fs::read(&recording_path).await.unwrap();
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut resp = httparse::Response::new(&mut headers);
 
-    resp.parse(&c).unwrap();
+    let scale = config.playback.delay_scale;
+    Ok(resp.map(|body| {
+        let chunks = if response.chunks.is_empty() {
+            vec![(Duration::ZERO, body)]
+        } else {
+            response.to_chunks()
+        };
+        http_utils::delayed(chunks, trailers, scale)
+    }))
+}
 
-    let mut response_builder = Response::builder().status(resp.code.unwrap());
+pub fn not_recorded(req: &Request<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    println!(
+        "Not Impl for {} {} {}",
+        501,
+        &req.method().to_string(),
+        &req.uri().path().to_string()
+    );
 
-    let method = req.method().clone();
-    let path = req.uri().path();
-    println!("playback for {} {} {}", resp.code.unwrap(), &method, &path);
+    let mut resp = Response::builder().status(501);
 
-    for header in resp.headers {
-        response_builder = response_builder.header(header.name, header.value);
+    if req.headers().get("accept").is_some() {
+        resp = resp.header("accept", req.headers().get("accept").unwrap());
     }
+    resp.body(http_utils::empty()).unwrap()
+}
 
-    let start_of_body = http_utils::start_of_body(&c);
-    let body: Bytes = c[start_of_body..].to_vec().into();
-    //<&[u8] as TryInto<Bytes>>::try_into(c[start_of_body..]).unwrap().clone();
-    let response = response_builder.body(http_utils::full(body)).unwrap();
+/// The response to a request whose tape can't be read, it has to be fixed or
+/// recorded again.
+pub fn unreadable_tape(
+    req: &Request<Bytes>,
+    recording_name: &str,
+    err: &str,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    println!(
+        "error    for 500 {} {}: can't read {}, {}",
+        req.method(),
+        req.uri().path(),
+        recording_name,
+        err
+    );
+    Response::builder()
+        .status(500)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header("x-middleman-error", "tape")
+        .body(http_utils::full(format!(
+            "middleman: can't read {}, {}\n",
+            recording_name, err
+        )))
+        .unwrap()
+}
 
-    Ok(response)
+/// The response to a request that could not be sent upstream: its tape with
+/// `fallback_to_tape`, when it has one, or else a gateway error.
+pub async fn upstream_failed(
+    config: &Config,
+    req: &Request<Bytes>,
+    err: UpstreamError,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    println!(
+        "error    for {} {} {}: {}",
+        err.status().as_u16(),
+        req.method(),
+        req.uri().path(),
+        err
+    );
+    if config.fallback_to_tape && recording_exists(&recording_name(config, req)) {
+        return replay(config, req).await;
+    }
+    Ok(err.response())
 }
 
+/// Send a request upstream and stream the response to the client, the response is
+/// recorded to a tape once its whole body has been received.
+pub async fn record_upstream(
+    config: &Config,
+    req: Request<Bytes>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, UpstreamError> {
+    let recording_path = recording_name(config, &req);
+    // Marked before the response is in, so that in sequential mode identical requests
+    // made while it streams are recorded as well
+    let append = config.playback.sequential && !config.session.mark_recorded(&recording_path);
+
+    let resp = make_request(config, req.clone().map(http_utils::full)).await?;
+
+    let mut head = Response::new(());
+    *head.status_mut() = resp.status();
+    *head.version_mut() = resp.version();
+    *head.headers_mut() = resp.headers().clone();
+
+    let (resp, recorded) = tee::tee(resp, Some(config.max_record_body_size));
+    let config = config.clone();
+    tokio::task::spawn(async move {
+        if let Ok(recorded) = recorded.await {
+            let resp = head.map(|_| tee::concat(&recorded.chunks));
+            record(&config, req, resp, &recorded, append).await;
+        }
+    });
+
+    Ok(resp)
+}
+
+// Responses without a length, and event streams, are replayed chunk by chunk
+fn is_streamed(resp: &Response<Bytes>, chunks: &tee::Chunks) -> bool {
+    let event_stream = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"));
+    chunks.len() > 1 && (event_stream || !resp.headers().contains_key(CONTENT_LENGTH))
+}
+
+/// Write a response to the tape for a request, `append` adds it to the interactions
+/// already on the tape instead of replacing them.
 pub async fn record(
-    config: &config::Config,
-    req: Request<BoxBody<Bytes, hyper::Error>>,
-    resp: Response<BoxBody<Bytes, hyper::Error>>,
-) -> Result<(), hyper::Error> {
+    config: &Config,
+    req: Request<Bytes>,
+    mut resp: Response<Bytes>,
+    recorded: &tee::Recorded,
+    append: bool,
+) {
     let method = req.method().clone();
     let path = req.uri().path();
 
@@ -83,104 +281,332 @@ pub async fn record(
         &method,
         &path
     );
-    let recording_path = recording_name(&config.tapes, &req);
-    fs::create_dir_all(format!("{}/{}", &config.tapes, &path))
-        .await
-        .expect("Failed to create a tape directory");
+    let recording_path = recording_name(config, &req);
 
-    let mut file = tokio::fs::File::create(&recording_path)
-        .await
-        .expect("Could not write to the tapes directory");
-
-    let (resp, new_resp) = clone::clone_bytes_response(resp).await?;
-    let (_parts, body) = new_resp.into_parts();
+    let streamed = is_streamed(&resp, &recorded.chunks);
+    let bodiless = compression::is_bodiless(&method, resp.status());
+    if !bodiless {
+        let len = resp.body().len();
+        compression::normalize_framing(resp.headers_mut(), len);
+    }
 
-    let preamble = format!(
-        "{:?} {} {}\r\n",
-        &resp.version(),
-        &resp.status().as_str(),
-        &resp.status().canonical_reason().or(Some("")).unwrap()
-    );
+    let mut content_encoding = None;
+    if config.decompress && !bodiless {
+        (resp, content_encoding) = compression::decompress(resp);
+    }
 
-    let _ = file.write_all(preamble.as_bytes()).await;
-    for (name, value) in resp.headers() {
-        let header = format!("{}:{}\r\n", name, value.to_str().unwrap());
-        let _ = file.write_all(header.as_bytes()).await;
+    // Chunk boundaries are lost when the body is decompressed
+    let keep_chunks = streamed && content_encoding.is_none();
+    if keep_chunks {
+        resp.headers_mut().remove(CONTENT_LENGTH);
     }
-    let _ = file.write_all("\r\n".as_bytes()).await;
 
-    let x = body.collect().await?.aggregate();
-    let body = clone::clone_body(x);
+    let mut tape = Tape::new(&req, &resp);
+    let response = &mut tape.interactions[0].response;
+    if keep_chunks {
+        response.set_chunks(resp.headers(), &recorded.chunks);
+    }
+    if let Some(trailers) = &recorded.trailers {
+        response.trailers = tape::headers_to_vec(trailers);
+    }
+    response.content_encoding = content_encoding;
+    let interaction = &mut tape.interactions[0];
+    config
+        .headers
+        .record
+        .drop_from(&mut interaction.request.headers);
+    config
+        .headers
+        .record
+        .apply(&mut interaction.response.headers);
+    let descriptors = config
+        .descriptors
+        .as_ref()
+        .filter(|_| grpc::is_grpc(resp.headers()));
+    if let Some(descriptors) = descriptors {
+        grpc::decode_messages(descriptors, interaction);
+    }
+    let grpc_messages = (
+        interaction.request.grpc_messages.clone(),
+        interaction.response.grpc_messages.clone(),
+    );
+    config.redaction.redact(interaction);
+    if let Some(descriptors) = descriptors {
+        // The bodies hold the same messages, so they're redacted by encoding them again
+        let request = interaction.request.grpc_messages != grpc_messages.0;
+        let response = interaction.response.grpc_messages != grpc_messages.1;
+        if request || response {
+            if let Err(e) = grpc::encode_messages(descriptors, interaction, request, response) {
+                println!(
+                    "Not recording {} {}, the redacted messages can't be encoded: {}",
+                    method, path, e
+                );
+                return;
+            }
+        }
+    }
+    // The chunks hold the whole body, it's not stored twice
+    if !interaction.response.chunks.is_empty() {
+        interaction.response.body = Body::Text(String::new());
+    }
 
-    let _ = file.write_all(&body).await;
+    let lock = config.session.tape_lock(&recording_path);
+    let _guard = lock.lock().await;
+    if append {
+        match tape::load(&recording_path, &req).await {
+            Ok(Some(mut recorded)) => {
+                recorded.interactions.append(&mut tape.interactions);
+                tape = recorded;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                // Written over, the tape would lose what it has
+                println!("Not recording to {}, {}", recording_path, err);
+                return;
+            }
+        }
+    }
+    tape::save(&recording_path, &tape).await;
+}
 
-    Ok(())
+// HTTP/2 requests name the upstream in the URI instead of a `Host` header, the
+// upstream itself is named when the `Host` header is not a valid authority. A
+// request already in this form is left as it is.
+fn absolute_form(upstream: &Upstream, req: &mut Request<BoxBody<Bytes, hyper::Error>>) {
+    let scheme = if upstream.tls { "https" } else { "http" };
+    let authority = req
+        .headers_mut()
+        .remove(HOST)
+        .and_then(|host| Authority::try_from(host.as_bytes()).ok())
+        .or_else(|| req.uri().authority().cloned())
+        .map(|authority| authority.to_string())
+        .unwrap_or_else(|| format!("{}:{}", upstream.host, upstream.port));
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    if let Ok(uri) = format!("{}://{}{}", scheme, authority, path).parse() {
+        *req.uri_mut() = uri;
+    }
+    *req.version_mut() = Version::HTTP_2;
 }
 
-pub async fn make_request_insecure(
-    config: &Config,
-    req: Request<BoxBody<Bytes, hyper::Error>>,
-) -> Result<Response<Incoming>, hyper::Error> {
-    let host = config.upstream_ip.clone();
-    let port = config.upstream_port;
+async fn handshake<T>(io: T, http2: bool) -> Result<Sender, hyper::Error>
+where
+    T: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    if http2 {
+        let (sender, conn) = http2::handshake(TokioExecutor, io).await?;
 
-    let stream = TcpStream::connect((host, port)).await.unwrap();
-    let io = TokioIo::new(stream);
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                println!("Connection failed: {:?}", err);
+            }
+        });
 
-    let (mut sender, conn) = Builder::new()
+        return Ok(Sender::Http2(sender));
+    }
+
+    let (sender, conn) = http1::Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
         .handshake(io)
         .await?;
 
     tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
+        if let Err(err) = conn.with_upgrades().await {
             println!("Connection failed: {:?}", err);
         }
     });
 
-    sender.send_request(req).await
+    Ok(Sender::Http1(sender))
 }
 
-pub async fn make_request_secure(
+// Send a request on a connection, HTTP/1.1 connections go back to the pool once
+// the response has been read
+async fn send(
     config: &Config,
-    req: Request<BoxBody<Bytes, hyper::Error>>,
-) -> Result<Response<Incoming>, hyper::Error> {
-    let ip = config.upstream_ip.clone();
-    let port = config.upstream_port;
-
-    let stream = TcpStream::connect((ip, port)).await.unwrap();
+    upstream: &Upstream,
+    sender: Sender,
+    mut req: Request<pool::Body>,
+) -> Result<Response<Incoming>, TrySendError<Request<pool::Body>>> {
+    match sender {
+        Sender::Http2(mut sender) => {
+            absolute_form(upstream, &mut req);
+            sender.try_send_request(req).await
+        }
+        Sender::Http1(mut sender) => {
+            // A request given back by an HTTP/2 connection is in HTTP/2 form
+            let req = http_utils::origin_form(req);
+            let resp = sender.try_send_request(req).await?;
+            config.pool.checkin(upstream, sender);
+            Ok(resp)
+        }
+    }
+}
 
-    let native_connector = NativeTlsConnector::builder().build().unwrap();
-    let stream = TlsConnector::from(native_connector)
-        .connect(&config.upstream, stream)
-        .await
-        .unwrap();
+// Connect to the first address of the upstream that accepts the connection
+async fn connect(config: &Config, upstream: &Upstream) -> Result<TcpStream, UpstreamError> {
+    let ips = config.resolver.lookup(&upstream.host).await.map_err(|e| {
+        UpstreamError::Resolve(format!("could not resolve {}: {}", upstream.host, e))
+    })?;
+
+    let mut errors = vec![];
+    for ip in ips {
+        match TcpStream::connect((ip, upstream.port)).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => errors.push(format!("{}: {}", ip, err)),
+        }
+    }
+    Err(UpstreamError::Connect(format!(
+        "could not connect to {}:{} ({})",
+        upstream.host,
+        upstream.port,
+        errors.join(", ")
+    )))
+}
 
+pub async fn handshake_insecure(
+    upstream: &Upstream,
+    stream: TcpStream,
+    websocket: bool,
+) -> Result<Sender, UpstreamError> {
     let io = TokioIo::new(stream);
 
-    let (mut sender, conn) = Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .handshake(io)
-        .await?;
+    // WebSocket upgrades only exist in HTTP/1.1
+    Ok(handshake(io, upstream.h2c && !websocket).await?)
+}
 
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
-        }
-    });
+pub async fn handshake_secure(
+    upstream: &Upstream,
+    stream: TcpStream,
+    websocket: bool,
+) -> Result<Sender, UpstreamError> {
+    let tls_error = |e: native_tls::Error| {
+        UpstreamError::Tls(format!("TLS with {} failed: {}", upstream.host, e))
+    };
+
+    let mut native_connector = NativeTlsConnector::builder();
+    // WebSocket upgrades only exist in HTTP/1.1
+    if !websocket {
+        native_connector.request_alpns(&["h2", "http/1.1"]);
+    }
+    let stream = TlsConnector::from(native_connector.build().map_err(tls_error)?)
+        .connect(&upstream.host, stream)
+        .await
+        .map_err(tls_error)?;
+
+    let http2 = stream
+        .get_ref()
+        .negotiated_alpn()
+        .ok()
+        .flatten()
+        .is_some_and(|protocol| protocol == b"h2");
+    let io = TokioIo::new(stream);
 
-    sender.send_request(req).await
+    Ok(handshake(io, http2).await?)
 }
 
+/// Send a request to its upstream, giving up once there is no response head within
+/// the upstream timeout.
 pub async fn make_request(
     config: &Config,
     req: Request<BoxBody<Bytes, hyper::Error>>,
-) -> Result<Response<Incoming>, hyper::Error> {
-    if config.upstream_tls {
-        return make_request_secure(config, req).await;
+) -> Result<Response<Incoming>, UpstreamError> {
+    let timeout = match config.upstream_timeout {
+        Some(timeout) => timeout,
+        None => return send_upstream(config, req).await,
+    };
+
+    let upstream = routes::upstream(config, &req)
+        .map(|upstream| format!("{}:{}", upstream.host, upstream.port));
+    match tokio::time::timeout(timeout, send_upstream(config, req)).await {
+        Ok(resp) => resp,
+        Err(_) => Err(UpstreamError::Timeout(format!(
+            "no response from {} within {:?}",
+            upstream.unwrap_or_default(),
+            timeout
+        ))),
+    }
+}
+
+async fn send_upstream(
+    config: &Config,
+    mut req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> Result<Response<Incoming>, UpstreamError> {
+    // Requests without an upstream never get here, `record_mode` makes them replay only
+    let upstream = routes::upstream(config, &req)
+        .expect("A request without an upstream")
+        .clone();
+
+    // Upgraded connections can not be used again, so WebSocket upgrades get a
+    // connection of their own
+    let websocket = websocket::is_upgrade(&req);
+    if !websocket {
+        if let Some(sender) = config.pool.checkout(&upstream) {
+            match send(config, &upstream, sender, req).await {
+                Ok(resp) => return Ok(resp),
+                // The connection was closed, or an HTTP/2 connection went away, before
+                // the request could be sent on it
+                Err(mut err) => match err.take_message() {
+                    Some(message) => req = message,
+                    None => return Err(err.into_error().into()),
+                },
+            }
+        }
+    }
+
+    let stream = connect(config, &upstream).await?;
+    let sender = if upstream.tls {
+        handshake_secure(&upstream, stream, websocket).await?
     } else {
-        return make_request_insecure(config, req).await;
+        handshake_insecure(&upstream, stream, websocket).await?
+    };
+    if let Sender::Http2(sender) = &sender {
+        config.pool.share(&upstream, sender);
+    }
+
+    send(config, &upstream, sender, req)
+        .await
+        .map_err(|err| err.into_error().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(path: &str) -> String {
+        let req = Request::builder().uri(path).body(Bytes::new()).unwrap();
+        recording_name_in(
+            "tapes",
+            &MatchConfig::default(),
+            &Redaction::default(),
+            &req,
+        )
+    }
+
+    #[test]
+    fn names_the_root() {
+        assert_eq!(name("/"), "tapes/@GET");
+    }
+
+    #[test]
+    fn names_a_trailing_slash() {
+        assert_eq!(name("/a"), "tapes/a/@GET");
+        assert_eq!(name("/a/"), "tapes/a/@/@GET");
+        assert_eq!(name("/a//b"), "tapes/a/@/b/@GET");
+    }
+
+    #[test]
+    fn keeps_paths_apart_from_methods() {
+        assert_eq!(name("/a/GET"), "tapes/a/GET/@GET");
+        assert_ne!(name("/a/GET"), name("/a"));
+    }
+
+    #[test]
+    fn keeps_tapes_inside_the_directory() {
+        assert_eq!(name("/../a"), "tapes/%2E%2E/a/@GET");
+        assert_eq!(name("/@cassettes"), "tapes/%40cassettes/@GET");
     }
 }
@@ -0,0 +1,275 @@
+use crate::tape::{Body, Interaction, RecordedResponse};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The `[redaction]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RedactionConfig {
+    /// Request and response headers whose values are redacted
+    pub headers: Vec<String>,
+    /// Query parameters whose values are redacted from the request URI
+    pub query_params: Vec<String>,
+    /// Dot separated paths into JSON bodies, `*` matches any key or array element
+    pub json_paths: Vec<String>,
+    /// Regular expressions, matches in headers and text bodies are redacted
+    pub patterns: Vec<String>,
+    /// Values to put in place of the placeholders when replaying, by header name,
+    /// JSON path or `pattern`
+    pub replay: HashMap<String, String>,
+}
+
+/// Removes secrets from interactions before they are written to a tape, leaving
+/// `[redacted:<key>]` placeholders that can be substituted on replay.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    headers: Vec<String>,
+    query_params: Vec<String>,
+    json_paths: Vec<Vec<String>>,
+    patterns: Vec<Regex>,
+    replay: HashMap<String, String>,
+}
+
+fn placeholder(key: &str) -> String {
+    format!("[redacted:{}]", key)
+}
+
+impl Redaction {
+    pub fn new(config: RedactionConfig) -> Result<Self, regex::Error> {
+        Ok(Redaction {
+            headers: config.headers.iter().map(|h| h.to_lowercase()).collect(),
+            query_params: config.query_params,
+            json_paths: config
+                .json_paths
+                .iter()
+                .map(|path| path.split('.').map(String::from).collect())
+                .collect(),
+            patterns: config
+                .patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            replay: config.replay,
+        })
+    }
+
+    pub fn redact(&self, interaction: &mut Interaction) {
+        interaction.request.uri = self.redact_uri(&interaction.request.uri);
+        self.redact_headers(&mut interaction.request.headers);
+        self.redact_body(&mut interaction.request.body);
+        self.redact_headers(&mut interaction.response.headers);
+        self.redact_headers(&mut interaction.response.trailers);
+        let body = interaction.response.body.clone();
+        self.redact_body(&mut interaction.response.body);
+        // A secret can be cut in half by chunks, and a chunk of JSON is not JSON, so
+        // a redacted body is replayed in one piece instead
+        if interaction.response.body != body {
+            interaction.response.chunks.clear();
+        }
+        for message in interaction.response.messages.iter_mut() {
+            self.redact_body(&mut message.data);
+        }
+        let grpc_messages = interaction
+            .request
+            .grpc_messages
+            .iter_mut()
+            .chain(interaction.response.grpc_messages.iter_mut());
+        for message in grpc_messages {
+            for path in &self.json_paths {
+                redact_json(message, path, &path.join("."));
+            }
+        }
+    }
+
+    /// Whether the value of a header is a secret, that must not reach a tape in
+    /// plain text, its name included.
+    pub fn is_secret_header(&self, name: &str, value: &str) -> bool {
+        self.headers.contains(&name.to_lowercase()) || self.matches_pattern(value)
+    }
+
+    /// Whether the value of a query parameter is a secret.
+    pub fn is_secret_query_param(&self, name: &str, value: &str) -> bool {
+        self.query_params.iter().any(|p| p == name) || self.matches_pattern(value)
+    }
+
+    fn matches_pattern(&self, value: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(value))
+    }
+
+    fn redact_uri(&self, uri: &str) -> String {
+        let (path, query) = match uri.split_once('?') {
+            Some(split) => split,
+            None => return self.redact_patterns(uri),
+        };
+        let query: Vec<String> = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, _)) if self.query_params.iter().any(|p| p == name) => {
+                    format!("{}={}", name, placeholder(name))
+                }
+                _ => param.to_string(),
+            })
+            .collect();
+        self.redact_patterns(&format!("{}?{}", path, query.join("&")))
+    }
+
+    fn redact_headers(&self, headers: &mut [(String, String)]) {
+        for (name, value) in headers.iter_mut() {
+            if self.headers.contains(&name.to_lowercase()) {
+                *value = placeholder(name);
+            } else {
+                *value = self.redact_patterns(value);
+            }
+        }
+    }
+
+    /// Redact a body, or a message, the way it is redacted on a tape.
+    pub fn redact_body(&self, body: &mut Body) {
+        let text = match body {
+            Body::Text(text) => text,
+            // Binary bodies are stored as they are
+            Body::Base64(_) => return,
+        };
+
+        if !self.json_paths.is_empty() {
+            if let Ok(mut json) = serde_json::from_str::<Value>(text) {
+                let mut redacted = false;
+                for path in &self.json_paths {
+                    redacted |= redact_json(&mut json, path, &path.join("."));
+                }
+                // Only re-serialize when something changed, to keep the body as it was otherwise
+                if redacted {
+                    *text = json.to_string();
+                }
+            }
+        }
+
+        *text = self.redact_patterns(text);
+    }
+
+    fn redact_patterns(&self, value: &str) -> String {
+        let mut value = value.to_string();
+        for pattern in &self.patterns {
+            value = pattern
+                .replace_all(&value, placeholder("pattern").as_str())
+                .to_string();
+        }
+        value
+    }
+
+    /// Put the configured replay values in place of the placeholders in a response.
+    pub fn restore(&self, response: &mut RecordedResponse) {
+        if self.replay.is_empty() {
+            return;
+        }
+
+        for (_, value) in response.headers.iter_mut().chain(&mut response.trailers) {
+            *value = self.substitute(value);
+        }
+        let chunks = response.chunks.iter_mut().map(|chunk| &mut chunk.data);
+        let messages = response
+            .messages
+            .iter_mut()
+            .map(|message| &mut message.data);
+        for body in std::iter::once(&mut response.body)
+            .chain(chunks)
+            .chain(messages)
+        {
+            if let Body::Text(text) = body {
+                *text = self.substitute(text);
+            }
+        }
+    }
+
+    fn substitute(&self, value: &str) -> String {
+        let mut value = value.to_string();
+        for (key, replacement) in &self.replay {
+            value = value.replace(&placeholder(key), replacement);
+        }
+        value
+    }
+}
+
+// Replace the values at a path with a placeholder, returns whether anything was replaced
+fn redact_json(json: &mut Value, path: &[String], key: &str) -> bool {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            *json = Value::String(placeholder(key));
+            return true;
+        }
+    };
+
+    let children: Vec<&mut Value> = match json {
+        Value::Object(map) if first == "*" => map.values_mut().collect(),
+        Value::Object(map) => map.get_mut(first).into_iter().collect(),
+        Value::Array(items) if first == "*" => items.iter_mut().collect(),
+        Value::Array(items) => first
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| items.get_mut(i))
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    };
+
+    let mut redacted = false;
+    for child in children {
+        redacted |= redact_json(child, rest, key);
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::{Chunk, Tape};
+    use bytes::Bytes;
+    use http::{Request, Response};
+
+    fn chunked(parts: &[&str]) -> Interaction {
+        let req = Request::new(Bytes::new());
+        let resp = Response::new(Bytes::from(parts.concat()));
+        let mut interaction = Tape::new(&req, &resp).interactions.remove(0);
+        interaction.response.chunks = parts
+            .iter()
+            .map(|part| Chunk {
+                offset_ms: 0,
+                data: Body::Text(part.to_string()),
+            })
+            .collect();
+        interaction
+    }
+
+    #[test]
+    fn redacts_a_secret_split_across_chunks() {
+        let redaction = Redaction::new(RedactionConfig {
+            patterns: vec!["sk_live_[a-z]+".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut interaction = chunked(&["{\"token\": \"sk_li", "ve_abc\"}"]);
+        redaction.redact(&mut interaction);
+
+        assert!(interaction.response.chunks.is_empty());
+        assert_eq!(
+            interaction.response.body,
+            Body::Text("{\"token\": \"[redacted:pattern]\"}".to_string())
+        );
+    }
+
+    #[test]
+    fn keeps_the_chunks_of_a_body_without_secrets() {
+        let redaction = Redaction::new(RedactionConfig {
+            json_paths: vec!["token".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut interaction = chunked(&["{\"n\": ", "1}"]);
+        redaction.redact(&mut interaction);
+
+        assert_eq!(interaction.response.chunks.len(), 2);
+    }
+}
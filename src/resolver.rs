@@ -0,0 +1,117 @@
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig as DnsConfig, ResolverOpts};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+/// Where upstream hosts are looked up.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The nameservers of the system, from /etc/resolv.conf
+    #[default]
+    System,
+    Google,
+    Cloudflare,
+}
+
+/// The `[resolver]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ResolverConfig {
+    pub source: Source,
+    /// Nameservers used instead of the source, as `ip` or `ip:port`
+    pub nameservers: Vec<String>,
+    /// Look hosts up in the hosts file first
+    pub hosts_file: bool,
+    /// Addresses for hosts that are never looked up
+    pub overrides: HashMap<String, IpAddr>,
+}
+
+/// Looks up the addresses of upstream hosts when a connection is made to them.
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+    overrides: HashMap<String, IpAddr>,
+    // The addresses last found for each host
+    resolved: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("overrides", &self.overrides)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Resolver {
+    /// A resolver for the `[resolver]` table. Nothing is looked up until the first
+    /// connection, so replaying works without DNS.
+    pub fn new(config: ResolverConfig) -> Result<Self, String> {
+        let (mut dns_config, mut opts) = match config.source {
+            Source::System => read_system_conf().map_err(|e| e.to_string())?,
+            Source::Google => (DnsConfig::google(), ResolverOpts::default()),
+            Source::Cloudflare => (DnsConfig::cloudflare(), ResolverOpts::default()),
+        };
+
+        if !config.nameservers.is_empty() {
+            let mut nameservers = NameServerConfigGroup::new();
+            for nameserver in &config.nameservers {
+                let addr = nameserver
+                    .parse::<SocketAddr>()
+                    .or_else(|_| nameserver.parse::<IpAddr>().map(|ip| (ip, 53).into()))
+                    .map_err(|_| format!("Invalid nameserver `{}`", nameserver))?;
+                nameservers.merge(NameServerConfigGroup::from_ips_clear(
+                    &[addr.ip()],
+                    addr.port(),
+                    true,
+                ));
+            }
+            dns_config = DnsConfig::from_parts(dns_config.domain().cloned(), vec![], nameservers);
+        }
+
+        // The hosts file often points the upstream at middleman itself, so it is
+        // only used when asked for
+        opts.use_hosts_file = config.hosts_file;
+
+        Ok(Resolver {
+            resolver: TokioAsyncResolver::tokio(dns_config, opts),
+            overrides: config.overrides,
+            resolved: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The addresses of a host, in the order they should be tried. Answers are
+    /// cached for as long as their TTL, after that the host is looked up again.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        // IPv6 addresses are in brackets in URIs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Some(ip) = self.overrides.get(host) {
+            return Ok(vec![*ip]);
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let ips: Vec<IpAddr> = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| e.to_string())?
+            .iter()
+            .collect();
+        if ips.is_empty() {
+            return Err(format!("No addresses found for {}", host));
+        }
+
+        let mut resolved = self.resolved.lock().unwrap();
+        if resolved.get(host) != Some(&ips) {
+            println!("Resolved {} to {:?}", host, ips);
+            resolved.insert(host.to_string(), ips.clone());
+        }
+        Ok(ips)
+    }
+}
@@ -0,0 +1,214 @@
+use crate::config::Config;
+use crate::{cassette, filename, http_utils};
+use http::header::HOST;
+use http::uri::{Authority, Scheme};
+use http::{Request, Version};
+use regex::Regex;
+use serde::Deserialize;
+
+/// A server requests are sent to.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Talk HTTP/2 with prior knowledge to a cleartext upstream
+    pub h2c: bool,
+}
+
+impl Upstream {
+    pub fn new(host: String, tls: bool, mut port: u16, h2c: bool) -> Self {
+        if tls && port == 80 {
+            // Yes... if a user actually wants to use 80 with tls, it won't work
+            port = 443;
+        }
+
+        Upstream {
+            host,
+            port,
+            tls,
+            h2c,
+        }
+    }
+}
+
+/// A `[[routes]]` entry in middleman.toml. A request takes the first route whose
+/// conditions all match.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteConfig {
+    /// Matches paths starting with this prefix
+    pub path_prefix: Option<String>,
+    /// Matches the `Host` header, with or without its port
+    pub host: Option<String>,
+    /// Matches paths matching this regular expression
+    pub path_regex: Option<String>,
+    pub upstream: String,
+    pub upstream_port: Option<u16>,
+    #[serde(default)]
+    pub upstream_tls: bool,
+    #[serde(default)]
+    pub upstream_h2c: bool,
+    /// The directory, inside the tapes directory, the route's tapes are stored in.
+    /// Defaults to the upstream host
+    pub tapes: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    path_prefix: Option<String>,
+    host: Option<String>,
+    path_regex: Option<Regex>,
+    pub upstream: Upstream,
+    pub tapes: String,
+}
+
+impl Route {
+    pub fn new(config: RouteConfig, upstream: Upstream) -> Result<Self, String> {
+        let tapes = config.tapes.unwrap_or_else(|| config.upstream.clone());
+        if !cassette::valid_name(&tapes) {
+            return Err(format!(
+                "invalid tapes directory `{}`, it may contain letters, digits, `-`, `_` and `.`",
+                tapes
+            ));
+        }
+        let path_regex = match config.path_regex {
+            Some(regex) => Some(Regex::new(&regex).map_err(|e| e.to_string())?),
+            None => None,
+        };
+
+        Ok(Route {
+            path_prefix: config.path_prefix,
+            host: config.host.map(|host| host.to_lowercase()),
+            path_regex,
+            upstream,
+            tapes,
+        })
+    }
+
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        let path = req.uri().path();
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+        let host_without_port = host.rsplit_once(':').map_or(host.as_str(), |(h, _)| h);
+
+        self.path_prefix
+            .as_ref()
+            .is_none_or(|prefix| path.starts_with(prefix))
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|h| *h == host || h == host_without_port)
+            && self
+                .path_regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(path))
+    }
+}
+
+/// The host a request to middleman as a forward proxy is for, kept in the
+/// extensions of the request.
+#[derive(Debug, Clone)]
+pub struct ForwardTarget {
+    pub upstream: Upstream,
+    /// The directory, inside the tapes directory, the host's tapes are stored in
+    pub tapes: String,
+}
+
+impl ForwardTarget {
+    /// The target for a host, tapes for its default port are stored without the port.
+    /// Credentials in the authority never end up in the name.
+    pub fn new(authority: &Authority, tls: bool) -> Self {
+        let default_port = if tls { 443 } else { 80 };
+        let port = authority.port_u16().unwrap_or(default_port);
+        let tapes = if port == default_port {
+            authority.host().to_string()
+        } else {
+            format!("{}:{}", authority.host(), port)
+        };
+
+        ForwardTarget {
+            upstream: Upstream {
+                host: authority.host().to_string(),
+                port,
+                tls,
+                h2c: false,
+            },
+            tapes: filename::encode_segment(&tapes),
+        }
+    }
+}
+
+/// Take the host out of the absolute URI of a request made to middleman as a
+/// forward proxy, and keep it as the request's [`ForwardTarget`]. Requests that
+/// already have a target, from an intercepted CONNECT tunnel, are left alone, as
+/// are HTTP/2 requests: they always carry the URI they're for, proxied or not.
+pub fn forward<T>(config: &Config, mut req: Request<T>) -> Request<T> {
+    if !config.forward_proxy
+        || req.version() == Version::HTTP_2
+        || req.extensions().get::<ForwardTarget>().is_some()
+    {
+        return req;
+    }
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => return req,
+    };
+
+    let tls = req.uri().scheme() == Some(&Scheme::HTTPS);
+    let target = ForwardTarget::new(&authority, tls);
+
+    req.headers_mut().remove("proxy-connection");
+    req.extensions_mut().insert(target);
+    http_utils::origin_form(req)
+}
+
+/// The route a request takes, if any matches.
+pub fn route<'a, T>(config: &'a Config, req: &Request<T>) -> Option<&'a Route> {
+    config.routes.iter().find(|route| route.matches(req))
+}
+
+/// The upstream a request is sent to: the host it is for when middleman is used as
+/// a forward proxy, the upstream of its route, or else the default upstream.
+pub fn upstream<'a, T>(config: &'a Config, req: &'a Request<T>) -> Option<&'a Upstream> {
+    if let Some(target) = req.extensions().get::<ForwardTarget>() {
+        return Some(&target.upstream);
+    }
+    match route(config, req) {
+        Some(route) => Some(&route.upstream),
+        None => config.upstream.as_ref(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tapes(authority: &str, tls: bool) -> String {
+        ForwardTarget::new(&authority.parse().unwrap(), tls).tapes
+    }
+
+    #[test]
+    fn names_hosts_on_their_default_port() {
+        assert_eq!(tapes("example.com", false), "example.com");
+        assert_eq!(tapes("example.com:443", true), "example.com");
+    }
+
+    #[test]
+    fn names_hosts_on_other_ports() {
+        assert_eq!(tapes("example.com:8080", false), "example.com%3A8080");
+        assert_eq!(tapes("example.com:80", true), "example.com%3A80");
+    }
+
+    #[test]
+    fn leaves_credentials_out() {
+        assert_eq!(tapes("user:pass@example.com", false), "example.com");
+        assert_eq!(
+            tapes("user:pass@example.com:8080", false),
+            "example.com%3A8080"
+        );
+    }
+}
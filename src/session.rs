@@ -0,0 +1,123 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What to replay once every interaction in a sequence has been replayed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OnExhausted {
+    /// Keep replaying the last interaction
+    #[default]
+    RepeatLast,
+    /// Start over at the first interaction
+    Cycle,
+    /// Respond with an error
+    Error,
+}
+
+/// The `[playback]` table in middleman.toml.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    /// Record repeated identical requests as a sequence and replay them in order
+    pub sequential: bool,
+    pub on_exhausted: OnExhausted,
+    /// Multiplies the delays between the chunks of a streamed response, 0 replays
+    /// them without delays
+    pub delay_scale: f64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            sequential: false,
+            on_exhausted: OnExhausted::default(),
+            delay_scale: 1.0,
+        }
+    }
+}
+
+/// State that lives for as long as middleman runs.
+#[derive(Debug, Default)]
+pub struct Session {
+    // Tapes recorded since middleman started
+    recorded: Mutex<HashSet<String>>,
+    // The index of the next interaction to replay, per tape
+    cursors: Mutex<HashMap<String, usize>>,
+    // The cassette selected through the admin API
+    cassette: Mutex<Option<String>>,
+    // Whether a cassette directory existed when it was first used
+    existing_cassettes: Mutex<HashMap<String, bool>>,
+    // Serializes writes to the same tape
+    tape_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Session {
+    pub fn cassette(&self) -> Option<String> {
+        self.cassette.lock().unwrap().clone()
+    }
+
+    /// Select a cassette, replaying its sequences from the start.
+    pub fn select_cassette(&self, cassette: Option<String>) {
+        *self.cassette.lock().unwrap() = cassette;
+        self.cursors.lock().unwrap().clear();
+    }
+
+    /// Did the cassette directory exist before middleman first used it?
+    pub fn cassette_existed(&self, dir: &str) -> bool {
+        *self
+            .existing_cassettes
+            .lock()
+            .unwrap()
+            .entry(dir.to_string())
+            .or_insert_with(|| Path::new(dir).exists())
+    }
+
+    /// Mark a tape as recorded since middleman started, returns whether it already was.
+    pub fn mark_recorded(&self, recording_name: &str) -> bool {
+        !self
+            .recorded
+            .lock()
+            .unwrap()
+            .insert(recording_name.to_string())
+    }
+
+    pub fn tape_lock(&self, recording_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.tape_locks
+            .lock()
+            .unwrap()
+            .entry(recording_name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Was the tape recorded since middleman started?
+    pub fn is_recorded(&self, recording_name: &str) -> bool {
+        self.recorded.lock().unwrap().contains(recording_name)
+    }
+
+    /// The index of the interaction to replay next from a tape with `len` interactions,
+    /// `None` when the sequence is exhausted and `on_exhausted` is `Error`.
+    pub fn next_interaction(
+        &self,
+        recording_name: &str,
+        len: usize,
+        on_exhausted: OnExhausted,
+    ) -> Option<usize> {
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = cursors.entry(recording_name.to_string()).or_insert(0);
+        let index = *cursor;
+        *cursor += 1;
+
+        if index < len {
+            return Some(index);
+        }
+
+        match on_exhausted {
+            OnExhausted::RepeatLast => Some(len - 1),
+            OnExhausted::Cycle => Some(index % len),
+            OnExhausted::Error => None,
+        }
+    }
+}
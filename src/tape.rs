@@ -0,0 +1,440 @@
+use crate::tee::Chunks;
+use crate::{compression, http_utils};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+/// The version of the tape format written by this version of middleman.
+pub const TAPE_VERSION: u32 = 1;
+
+/// A tape as it is stored on disk, the interactions recorded for one request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tape {
+    pub version: u32,
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub recorded_at: DateTime<Utc>,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// The messages of a gRPC call as JSON, when a descriptor set is configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grpc_messages: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    /// The content coding the body was decompressed from when it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// The body as it was streamed, replayed with the same delays
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+    /// The messages exchanged over a WebSocket connection
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<Message>,
+    /// Headers sent after the body, like `grpc-status`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(String, String)>,
+    /// The messages of a gRPC call as JSON, when a descriptor set is configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grpc_messages: Vec<serde_json::Value>,
+}
+
+/// A piece of a streamed body, and how long after the start of the response it arrived.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub offset_ms: u64,
+    pub data: Body,
+}
+
+/// Which side of a WebSocket connection sent a message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Client,
+    Server,
+}
+
+/// A WebSocket message, and how long after the connection was opened it was sent.
+/// Text messages are stored as text, binary messages as base64.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub direction: Direction,
+    pub offset_ms: u64,
+    pub data: Body,
+}
+
+/// A request or response body, kept as text when it is readable and base64 otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "encoding", content = "data", rename_all = "lowercase")]
+pub enum Body {
+    Text(String),
+    Base64(String),
+}
+
+impl Body {
+    pub fn new(headers: &HeaderMap, body: &[u8]) -> Self {
+        if is_text(headers) {
+            if let Ok(text) = std::str::from_utf8(body) {
+                return Body::Text(text.to_string());
+            }
+        }
+        Body::Base64(BASE64_STANDARD.encode(body))
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Body::Text(_) => Ok(()),
+            Body::Base64(data) => BASE64_STANDARD
+                .decode(data)
+                .map(|_| ())
+                .map_err(|e| format!("it has an invalid base64 body: {}", e)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            Body::Text(text) => Bytes::from(text.clone()),
+            Body::Base64(data) => BASE64_STANDARD
+                .decode(data)
+                .expect("Tape contains an invalid base64 body")
+                .into(),
+        }
+    }
+}
+
+// Content types, other than text/*, whose bodies are stored as text
+const TEXT_TYPES: [&str; 5] = [
+    "json",
+    "xml",
+    "javascript",
+    "x-www-form-urlencoded",
+    "graphql",
+];
+
+fn is_text(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type.to_lowercase(),
+        // Most APIs we talk to leave it out on empty or plain text bodies
+        None => return true,
+    };
+
+    content_type.starts_with("text/") || TEXT_TYPES.iter().any(|t| content_type.contains(t))
+}
+
+/// The headers as they are stored in a tape. Tapes are text, so values that are
+/// not valid UTF-8 are left out rather than stored changed.
+pub fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(
+            |(name, value)| match std::str::from_utf8(value.as_bytes()) {
+                Ok(value) => Some((name.to_string(), value.to_string())),
+                Err(_) => {
+                    println!("Not recording header {}, its value is not UTF-8", name);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+impl RecordedRequest {
+    pub fn new(req: &Request<Bytes>) -> Self {
+        RecordedRequest {
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: headers_to_vec(req.headers()),
+            body: Body::new(req.headers(), req.body()),
+            grpc_messages: vec![],
+        }
+    }
+
+    pub fn to_request(&self) -> Request<Bytes> {
+        let mut builder = Request::builder()
+            .method(self.method.as_str())
+            .uri(&self.uri);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(self.body.to_bytes())
+            .expect("Tape contains an invalid request")
+    }
+}
+
+impl RecordedResponse {
+    pub fn new(resp: &Response<Bytes>) -> Self {
+        RecordedResponse {
+            status: resp.status().as_u16(),
+            headers: headers_to_vec(resp.headers()),
+            body: Body::new(resp.headers(), resp.body()),
+            content_encoding: None,
+            chunks: vec![],
+            messages: vec![],
+            trailers: vec![],
+            grpc_messages: vec![],
+        }
+    }
+
+    /// Keep the chunks of a streamed body, so it can be replayed the way it arrived.
+    pub fn set_chunks(&mut self, headers: &HeaderMap, chunks: &Chunks) {
+        self.chunks = chunks
+            .iter()
+            .map(|(offset, data)| Chunk {
+                offset_ms: offset.as_millis() as u64,
+                data: Body::new(headers, data),
+            })
+            .collect();
+    }
+
+    pub fn trailers_map(&self) -> Option<HeaderMap> {
+        if self.trailers.is_empty() {
+            return None;
+        }
+        let mut trailers = HeaderMap::new();
+        for (name, value) in &self.trailers {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    trailers.append(name, value);
+                }
+                _ => println!("Ignoring invalid trailer {}: {}", name, value),
+            }
+        }
+        Some(trailers)
+    }
+
+    pub fn to_chunks(&self) -> Chunks {
+        self.chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    Duration::from_millis(chunk.offset_ms),
+                    chunk.data.to_bytes(),
+                )
+            })
+            .collect()
+    }
+
+    /// The response, replayed for a request with `method`.
+    pub fn to_response(&self, method: &Method) -> Response<Bytes> {
+        let body = self.body.to_bytes();
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        let mut resp = builder
+            .body(body)
+            .expect("Tape contains an invalid response");
+
+        // Redaction may have changed the length of the body, and tapes recorded by
+        // older versions can still say the body is chunked
+        let framed = resp.headers().contains_key(CONTENT_LENGTH)
+            || resp.headers().contains_key(TRANSFER_ENCODING);
+        if framed && !compression::is_bodiless(method, resp.status()) {
+            let len = resp.body().len();
+            compression::normalize_framing(resp.headers_mut(), len);
+        }
+        resp
+    }
+}
+
+impl Tape {
+    pub fn new(req: &Request<Bytes>, resp: &Response<Bytes>) -> Self {
+        Tape {
+            version: TAPE_VERSION,
+            interactions: vec![Interaction {
+                recorded_at: Utc::now(),
+                request: RecordedRequest::new(req),
+                response: RecordedResponse::new(resp),
+            }],
+        }
+    }
+}
+
+fn check_headers(headers: &[(String, String)]) -> Result<(), String> {
+    for (name, value) in headers {
+        HeaderName::try_from(name.as_str())
+            .map_err(|_| format!("it has an invalid header name `{}`", name))?;
+        HeaderValue::try_from(value.as_str())
+            .map_err(|_| format!("it has an invalid value for header {}", name))?;
+    }
+    Ok(())
+}
+
+impl Tape {
+    // Everything on a tape has to turn back into a request or response, tapes are
+    // committed and edited by hand
+    fn check(&self) -> Result<(), String> {
+        if self.interactions.is_empty() {
+            return Err("it has no interactions".to_string());
+        }
+        for interaction in &self.interactions {
+            let request = &interaction.request;
+            Method::from_bytes(request.method.as_bytes())
+                .map_err(|_| format!("it has an invalid method `{}`", request.method))?;
+            Uri::try_from(request.uri.as_str())
+                .map_err(|_| format!("it has an invalid URI `{}`", request.uri))?;
+            check_headers(&request.headers)?;
+            request.body.check()?;
+
+            let response = &interaction.response;
+            StatusCode::from_u16(response.status)
+                .map_err(|_| format!("it has an invalid status {}", response.status))?;
+            check_headers(&response.headers)?;
+            response.body.check()?;
+            let chunks = response.chunks.iter().map(|chunk| &chunk.data);
+            let messages = response.messages.iter().map(|message| &message.data);
+            for data in chunks.chain(messages) {
+                data.check()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// When the first interaction on the tape was recorded.
+    pub fn recorded_at(&self) -> DateTime<Utc> {
+        self.interactions
+            .iter()
+            .map(|interaction| interaction.recorded_at)
+            .min()
+            .unwrap_or_else(Utc::now)
+    }
+
+    /// Was the tape recorded longer than `max_age` ago?
+    pub fn is_stale(&self, max_age: Option<Duration>) -> bool {
+        match max_age {
+            Some(max_age) => Utc::now()
+                .signed_duration_since(self.recorded_at())
+                .to_std()
+                .is_ok_and(|age| age > max_age),
+            None => false,
+        }
+    }
+}
+
+/// The path of the structured tape for a recording name.
+pub fn path(recording_name: &str) -> String {
+    format!("{}.json", recording_name)
+}
+
+/// Does a tape, in either the structured or the raw format, exist for a recording name?
+pub fn exists(recording_name: &str) -> bool {
+    Path::new(&path(recording_name)).exists() || Path::new(recording_name).is_file()
+}
+
+pub async fn save(recording_name: &str, tape: &Tape) {
+    let path = path(recording_name);
+    fs::create_dir_all(Path::new(&path).parent().unwrap())
+        .await
+        .expect("Failed to create a tape directory");
+
+    let contents = serde_json::to_vec_pretty(tape).expect("Could not serialize the tape");
+    fs::write(&path, contents)
+        .await
+        .expect("Could not write to the tapes directory");
+}
+
+/// Load the tape for a recording name, `None` if there is none. Tapes in the raw
+/// HTTP/1.1 format written by older versions of middleman are converted, `req`
+/// stands in for the recorded request when the tape has no `.request` file next to
+/// it. Tapes that are broken, or written by a newer version, can't be loaded.
+pub async fn load(recording_name: &str, req: &Request<Bytes>) -> Result<Option<Tape>, String> {
+    if let Ok(contents) = fs::read(path(recording_name)).await {
+        let tape: Tape =
+            serde_json::from_slice(&contents).map_err(|e| format!("it is not a tape: {}", e))?;
+        if tape.version > TAPE_VERSION {
+            return Err(format!(
+                "it has version {}, this version of middleman only reads up to {}",
+                tape.version, TAPE_VERSION
+            ));
+        }
+        tape.check()?;
+        return Ok(Some(tape));
+    }
+
+    let raw = match fs::read(recording_name).await {
+        Ok(raw) => raw,
+        Err(_) => return Ok(None),
+    };
+    let request = match fs::read(format!("{}.request", recording_name)).await {
+        Ok(raw_request) => parse_raw_request(&raw_request),
+        Err(_) => Some(RecordedRequest::new(req)),
+    };
+    let (request, response) = match (request, parse_raw_response(&raw)) {
+        (Some(request), Some(response)) => (request, response),
+        _ => return Err("it is not a tape".to_string()),
+    };
+    let recorded_at = fs::metadata(recording_name)
+        .await
+        .and_then(|m| m.modified())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+
+    Ok(Some(Tape {
+        version: TAPE_VERSION,
+        interactions: vec![Interaction {
+            recorded_at,
+            request,
+            response,
+        }],
+    }))
+}
+
+// Tapes in the old format are raw HTTP responses, `None` for anything else
+fn parse_raw_response(raw: &[u8]) -> Option<RecordedResponse> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    if !matches!(resp.parse(raw), Ok(httparse::Status::Complete(_))) {
+        return None;
+    }
+
+    let mut builder = Response::builder().status(resp.code?);
+    for header in resp.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
+    let body = raw[http_utils::start_of_body(raw)..].to_vec();
+
+    Some(RecordedResponse::new(
+        &builder.body(Bytes::from(body)).ok()?,
+    ))
+}
+
+fn parse_raw_request(raw: &[u8]) -> Option<RecordedRequest> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    if !matches!(req.parse(raw), Ok(httparse::Status::Complete(_))) {
+        return None;
+    }
+
+    let mut builder = Request::builder().method(req.method?).uri(req.path?);
+    for header in req.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
+    let body = raw[http_utils::start_of_body(raw)..].to_vec();
+
+    Some(RecordedRequest::new(&builder.body(Bytes::from(body)).ok()?))
+}
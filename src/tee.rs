@@ -0,0 +1,222 @@
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::{HeaderMap, Response};
+use pin_project_lite::pin_project;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// The data frames of a body, with how long after the start of the response they arrived.
+pub type Chunks = Vec<(Duration, Bytes)>;
+
+/// The copy of a body kept by [`TeeBody`].
+#[derive(Debug, Default)]
+pub struct Recorded {
+    pub chunks: Chunks,
+    pub trailers: Option<HeaderMap>,
+}
+
+pin_project! {
+    /// A body that passes every frame on as it arrives, while keeping a copy.
+    /// Once the body ends, the copy is sent to the receiver returned by [`tee`].
+    pub struct TeeBody {
+        #[pin]
+        inner: Incoming,
+        started: Instant,
+        // `None` once the body got too big to keep
+        copy: Option<Recorded>,
+        len: usize,
+        max_size: Option<usize>,
+        done: Option<oneshot::Sender<Recorded>>,
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let frame = match this.inner.as_mut().poll_frame(cx) {
+            Poll::Ready(frame) => frame,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(copy), Some(trailers)) = (this.copy.as_mut(), frame.trailers_ref()) {
+                    copy.trailers = Some(trailers.clone());
+                }
+                if let (Some(copy), Some(data)) = (this.copy.as_mut(), frame.data_ref()) {
+                    *this.len += data.len();
+                    if this.max_size.is_some_and(|max| *this.len > max) {
+                        println!(
+                            "Not recording a body larger than {} bytes",
+                            this.max_size.unwrap_or_default()
+                        );
+                        *this.copy = None;
+                    } else {
+                        copy.chunks.push((this.started.elapsed(), data.clone()));
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                // An incomplete body is never recorded
+                *this.copy = None;
+            }
+            None => {}
+        }
+
+        // The body may not be polled again once the last frame is in
+        if frame.is_none() || this.inner.is_end_stream() {
+            if let (Some(copy), Some(done)) = (this.copy.take(), this.done.take()) {
+                let _ = done.send(copy);
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Join chunks back into a single body.
+pub fn concat(chunks: &Chunks) -> Bytes {
+    let mut body = BytesMut::new();
+    for (_, data) in chunks {
+        body.extend_from_slice(data);
+    }
+    body.freeze()
+}
+
+/// Stream a response to the client while keeping a copy of its body. The receiver
+/// gets the chunks and trailers of the body once it has been streamed, and is
+/// dropped when the body is larger than `max_size` or fails.
+pub fn tee(
+    resp: Response<Incoming>,
+    max_size: Option<usize>,
+) -> (
+    Response<BoxBody<Bytes, hyper::Error>>,
+    oneshot::Receiver<Recorded>,
+) {
+    let (done, recorded) = oneshot::channel();
+
+    let too_big = max_size.is_some_and(|max| {
+        resp.body()
+            .size_hint()
+            .exact()
+            .is_some_and(|len| len > max as u64)
+    });
+    if too_big {
+        println!(
+            "Not recording a body larger than {} bytes",
+            max_size.unwrap_or_default()
+        );
+    }
+
+    // Hyper never polls a body that is empty from the start, so its copy is sent now
+    if resp.body().is_end_stream() {
+        let _ = done.send(Recorded::default());
+        return (resp.map(|body| body.boxed()), recorded);
+    }
+
+    let resp = resp.map(|inner| {
+        TeeBody {
+            inner,
+            started: Instant::now(),
+            copy: if too_big {
+                None
+            } else {
+                Some(Recorded::default())
+            },
+            len: 0,
+            max_size,
+            done: Some(done),
+        }
+        .boxed()
+    });
+
+    (resp, recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_utils;
+    use crate::tokiort::TokioIo;
+    use hyper::client::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, StatusCode};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Get a response from a server that answers every request with `status` and `body`
+    async fn upstream_response(status: StatusCode, body: &'static str) -> Response<Incoming> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |_req| async move {
+                let mut resp = Response::new(http_utils::full(body));
+                *resp.status_mut() = status;
+                Ok::<_, hyper::Error>(resp)
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::task::spawn(conn);
+        let req = Request::builder()
+            .uri("/")
+            .body(http_utils::empty())
+            .unwrap();
+        sender.send_request(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn copies_the_body() {
+        let resp = upstream_response(StatusCode::OK, "hello").await;
+        let (resp, recorded) = tee(resp, None);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let recorded = recorded.await.unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(concat(&recorded.chunks), "hello");
+    }
+
+    #[tokio::test]
+    async fn copies_an_empty_body() {
+        let resp = upstream_response(StatusCode::NO_CONTENT, "").await;
+        let (resp, recorded) = tee(resp, None);
+
+        // Hyper writes the head of an empty response without polling its body
+        drop(resp);
+        let recorded = recorded.await.unwrap();
+        assert!(recorded.chunks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_a_body_larger_than_the_max_size() {
+        let resp = upstream_response(StatusCode::OK, "hello").await;
+        let (resp, recorded) = tee(resp, Some(3));
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+        assert!(recorded.await.is_err());
+    }
+}
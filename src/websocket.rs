@@ -0,0 +1,340 @@
+use crate::config::{Config, RecordMode};
+use crate::redact::Redaction;
+use crate::tape::{self, Body, Direction, Tape};
+use crate::tokiort::TokioIo;
+use crate::{cassette, http_utils, proxy};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use http::header::{
+    CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, UPGRADE,
+};
+use http::{HeaderValue, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::upgrade::Upgraded;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Is the request the opening handshake of a WebSocket connection?
+pub fn is_upgrade<T>(req: &Request<T>) -> bool {
+    let header_has = |name, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value
+                .to_str()
+                .unwrap_or("")
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    header_has(UPGRADE, "websocket") && header_has(CONNECTION, "upgrade")
+}
+
+// The message as it is stored in a tape, control messages aren't stored
+fn to_body(message: &Message) -> Option<Body> {
+    match message {
+        Message::Text(text) => Some(Body::Text(text.to_string())),
+        Message::Binary(data) => Some(Body::Base64(BASE64_STANDARD.encode(data))),
+        _ => None,
+    }
+}
+
+fn to_message(body: &Body) -> Message {
+    match body {
+        Body::Text(text) => Message::text(text.clone()),
+        Body::Base64(_) => Message::binary(body.to_bytes()),
+    }
+}
+
+async fn websocket(upgraded: Upgraded, role: Role) -> WebSocketStream<TokioIo<Upgraded>> {
+    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), role, None).await
+}
+
+/// Proxy, record or replay a WebSocket connection, the same way the record mode
+/// decides for plain requests.
+pub async fn handle(
+    config: &Config,
+    req: Request<Bytes>,
+    record_mode: RecordMode,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let passthrough = req
+        .headers()
+        .get("x-middleman-passthrough")
+        .is_some_and(|value| value != "false");
+    // Replaying only takes precedence over the passthrough header, as for plain requests
+    if record_mode == RecordMode::None {
+        return replay_connection(config, req).await;
+    }
+    if passthrough {
+        return relay_connection(config, req, false).await;
+    }
+
+    let recording_name = proxy::recording_name(config, &req);
+    let known = proxy::recording_exists(&recording_name);
+    let replay = match record_mode {
+        RecordMode::Once => known || cassette::sealed(config, &req),
+        RecordMode::NewEpisodes => known,
+        _ => false,
+    };
+
+    if replay {
+        replay_connection(config, req).await
+    } else {
+        relay_connection(config, req, true).await
+    }
+}
+
+// Open the connection upstream and pass the messages on in both directions
+async fn relay_connection(
+    config: &Config,
+    mut req: Request<Bytes>,
+    record: bool,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    // Compressed messages (permessage-deflate) can't be read, so no extensions are
+    // negotiated with the upstream
+    req.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let mut resp = match proxy::make_request(config, req.clone().map(http_utils::full)).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            println!(
+                "Failed to open the WebSocket connection for {}: {}",
+                req.uri().path(),
+                err
+            );
+            return Ok(err.response());
+        }
+    };
+
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        println!(
+            "upstream refused the WebSocket connection for {}",
+            req.uri().path()
+        );
+        return Ok(resp.map(|body| body.boxed()));
+    }
+
+    let upstream_upgrade = hyper::upgrade::on(&mut resp);
+    let (parts, _) = resp.into_parts();
+    let head = Response::from_parts(parts.clone(), Bytes::new());
+
+    let config = config.clone();
+    tokio::task::spawn(async move {
+        let (client, upstream) = match tokio::try_join!(client_upgrade, upstream_upgrade) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                eprintln!("upgrade error: {}", e);
+                return;
+            }
+        };
+        let client = websocket(client, Role::Server).await;
+        let upstream = websocket(upstream, Role::Client).await;
+        let messages = relay(client, upstream).await;
+
+        if record {
+            save(&config, req, head, messages).await;
+        }
+    });
+
+    Ok(Response::from_parts(parts, http_utils::empty()))
+}
+
+async fn relay<C, U>(client: WebSocketStream<C>, upstream: WebSocketStream<U>) -> Vec<tape::Message>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let mut messages = vec![];
+
+    loop {
+        let (direction, message) = tokio::select! {
+            message = client_rx.next() => (Direction::Client, message),
+            message = upstream_rx.next() => (Direction::Server, message),
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            _ => break,
+        };
+
+        if let Some(data) = to_body(&message) {
+            messages.push(tape::Message {
+                direction,
+                offset_ms: started.elapsed().as_millis() as u64,
+                data,
+            });
+        }
+
+        // Pings are answered on each side of the connection by itself
+        if !(message.is_text() || message.is_binary() || message.is_close()) {
+            continue;
+        }
+        let close = message.is_close();
+        let sent = match direction {
+            Direction::Client => upstream_tx.send(message).await,
+            Direction::Server => client_tx.send(message).await,
+        };
+        if sent.is_err() || close {
+            break;
+        }
+    }
+
+    // Completes the closing handshake on the side that started it
+    let _ = client_tx.close().await;
+    let _ = upstream_tx.close().await;
+    messages
+}
+
+async fn save(
+    config: &Config,
+    req: Request<Bytes>,
+    head: Response<Bytes>,
+    messages: Vec<tape::Message>,
+) {
+    println!(
+        "record   for {} {} {} ({} messages)",
+        head.status().as_u16(),
+        req.method(),
+        req.uri().path(),
+        messages.len()
+    );
+    let recording_path = proxy::recording_name(config, &req);
+
+    let mut tape = Tape::new(&req, &head);
+    let interaction = &mut tape.interactions[0];
+    interaction.response.messages = messages;
+    config
+        .headers
+        .record
+        .drop_from(&mut interaction.request.headers);
+    config
+        .headers
+        .record
+        .apply(&mut interaction.response.headers);
+    config.redaction.redact(interaction);
+
+    let lock = config.session.tape_lock(&recording_path);
+    let _guard = lock.lock().await;
+    tape::save(&recording_path, &tape).await;
+    config.session.mark_recorded(&recording_path);
+}
+
+// Answer the handshake ourselves, and replay the recorded server messages
+async fn replay_connection(
+    config: &Config,
+    mut req: Request<Bytes>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let recording_path = proxy::recording_name(config, &req);
+    let tape = match tape::load(&recording_path, &req).await {
+        Ok(Some(tape)) => tape,
+        Ok(None) => return Ok(proxy::not_recorded(&req)),
+        Err(err) => return Ok(proxy::unreadable_tape(&req, &recording_path, &err)),
+    };
+    let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            let mut resp = Response::new(http_utils::full("Missing Sec-WebSocket-Key"));
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(resp);
+        }
+    };
+
+    let mut response = tape.interactions[0].response.clone();
+    config.redaction.restore(&mut response);
+    let mut resp = response.to_response(req.method());
+    resp.headers_mut()
+        .insert(SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&key).unwrap());
+    // The messages are replayed uncompressed, whatever was negotiated when recording
+    resp.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+    config.headers.replay.apply_to_map(resp.headers_mut());
+
+    println!(
+        "playback for {} {} {} ({} messages)",
+        resp.status().as_u16(),
+        req.method(),
+        req.uri().path(),
+        response.messages.len()
+    );
+
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let scale = config.playback.delay_scale;
+    let redaction = config.redaction.clone();
+    tokio::task::spawn(async move {
+        match client_upgrade.await {
+            Ok(upgraded) => {
+                play(
+                    websocket(upgraded, Role::Server).await,
+                    response.messages,
+                    &redaction,
+                    scale,
+                )
+                .await
+            }
+            Err(e) => eprintln!("upgrade error: {}", e),
+        }
+    });
+
+    Ok(resp.map(http_utils::full))
+}
+
+// Server messages are sent with their recorded delays, up to the next client message
+// on the tape. The ones after it are sent once the client sends a matching message,
+// client messages are redacted like the ones on the tape before they're compared.
+async fn play<S>(
+    ws: WebSocketStream<S>,
+    messages: Vec<tape::Message>,
+    redaction: &Redaction,
+    scale: f64,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut tx, mut rx) = ws.split();
+    let mut cursor = 0;
+    let mut since = Instant::now();
+    let mut since_ms = 0;
+
+    loop {
+        while let Some(message) = messages
+            .get(cursor)
+            .filter(|message| message.direction == Direction::Server)
+        {
+            let delay = Duration::from_millis(message.offset_ms.saturating_sub(since_ms));
+            time::sleep_until(since + delay.mul_f64(scale.max(0.0))).await;
+            if tx.send(to_message(&message.data)).await.is_err() {
+                return;
+            }
+            cursor += 1;
+        }
+
+        let received = match rx.next().await {
+            Some(Ok(received)) if !received.is_close() => received,
+            _ => break,
+        };
+        let mut data = match to_body(&received) {
+            Some(data) => data,
+            None => continue,
+        };
+        redaction.redact_body(&mut data);
+
+        let matching = messages[cursor..]
+            .iter()
+            .position(|message| message.direction == Direction::Client && message.data == data);
+        match matching {
+            Some(i) => {
+                since = Instant::now();
+                since_ms = messages[cursor + i].offset_ms;
+                cursor += i + 1;
+            }
+            None => println!("No recorded reply for a WebSocket message"),
+        }
+    }
+
+    let _ = tx.close().await;
+}